@@ -0,0 +1,257 @@
+use crate::common::WithdrawalStatus;
+use crate::helper::{MintRecord, WithdrawRecord};
+use crate::history::{self, HistoryPage, HistoryQuery};
+use crate::{withdrawals, MINT_RECORDS, WITHDRAWALS, WITHDRAW_RECORDS};
+use candid::{CandidType, Encode, Principal};
+use ic_cdk::api;
+use ic_certified_map::{fork, labeled, labeled_hash, AsHashTree, HashTree, RbTree};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+thread_local! {
+    // Lives on the heap; `rebuild` recreates it from the stable records.
+    static TREE: RefCell<RbTree<Vec<u8>, Vec<u8>>> = RefCell::new(RbTree::new());
+}
+
+/// The certified data is the hash of the tree labeled with this.
+const LABEL: &[u8] = b"bridge";
+
+// Kinds of leaves. Each is followed by the big endian block index, so that
+// the leaves of one kind sort by block index.
+const MINT: &[u8] = b"mint/";
+const WITHDRAW: &[u8] = b"withdraw/";
+const WITHDRAWAL: &[u8] = b"withdrawal/";
+// Empty leaves indexing the mint and withdraw records of a principal, keyed
+// by the principal, length first, then the key of the record.
+const PRINCIPAL: &[u8] = b"principal/";
+
+/// A query result with what an agent needs to verify it: the certificate
+/// of the certified data and a witness revealing the returned leaves. The
+/// leaves hold the Candid encoding of the records, so the verified witness
+/// carries the records themselves. For history pages the witness covers the
+/// whole range of keys the page was read from, so an agent can also check
+/// that no record was left out.
+#[derive(CandidType, Debug)]
+pub struct Certified<T> {
+    pub data: T,
+    /// Only set in query calls.
+    pub certificate: Option<ByteBuf>,
+    /// CBOR encoded hash tree.
+    pub witness: ByteBuf,
+}
+
+fn leaf_key(kind: &[u8], block_index: u64) -> Vec<u8> {
+    [kind, &block_index.to_be_bytes()].concat()
+}
+
+fn index_key(owner: &Principal, kind: &[u8], block_index: u64) -> Vec<u8> {
+    let owner = owner.as_slice();
+    [
+        PRINCIPAL,
+        &[owner.len() as u8],
+        owner,
+        &leaf_key(kind, block_index),
+    ]
+    .concat()
+}
+
+fn encode<T: CandidType>(value: &T) -> Vec<u8> {
+    Encode!(value).expect("failed to encode a certified leaf")
+}
+
+fn set_certified_data(tree: &RbTree<Vec<u8>, Vec<u8>>) {
+    let root_hash = labeled_hash(LABEL, &tree.root_hash());
+    // Only a canister has certified data, tests just keep the tree.
+    if cfg!(not(test)) {
+        api::set_certified_data(&root_hash);
+    }
+}
+
+fn insert(leaves: Vec<(Vec<u8>, Vec<u8>)>) {
+    TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for (key, leaf) in leaves {
+            tree.insert(key, leaf);
+        }
+        set_certified_data(&tree);
+    });
+}
+
+fn mint_leaves(record: &MintRecord) -> Vec<(Vec<u8>, Vec<u8>)> {
+    vec![
+        (leaf_key(MINT, record.block_index), encode(record)),
+        (
+            index_key(&record.to.owner, MINT, record.block_index),
+            vec![],
+        ),
+    ]
+}
+
+fn withdraw_leaves(record: &WithdrawRecord) -> Vec<(Vec<u8>, Vec<u8>)> {
+    vec![
+        (leaf_key(WITHDRAW, record.block_index), encode(record)),
+        (
+            index_key(&record.from, WITHDRAW, record.block_index),
+            vec![],
+        ),
+    ]
+}
+
+pub fn certify_mint(record: &MintRecord) {
+    insert(mint_leaves(record));
+}
+
+pub fn certify_withdraw(record: &WithdrawRecord) {
+    insert(withdraw_leaves(record));
+}
+
+pub fn certify_withdrawal(status: &WithdrawalStatus) {
+    insert(vec![(
+        leaf_key(WITHDRAWAL, status.block_index),
+        encode(status),
+    )]);
+}
+
+/// Recreates the tree from the stable records and certifies its root.
+pub fn rebuild() {
+    let mut tree = RbTree::new();
+    MINT_RECORDS.with(|records| {
+        for (_, record) in records.borrow().iter() {
+            for (key, leaf) in mint_leaves(&record) {
+                tree.insert(key, leaf);
+            }
+        }
+    });
+    WITHDRAW_RECORDS.with(|records| {
+        for (_, record) in records.borrow().iter() {
+            for (key, leaf) in withdraw_leaves(&record) {
+                tree.insert(key, leaf);
+            }
+        }
+    });
+    WITHDRAWALS.with(|withdrawals| {
+        for (block_index, withdrawal) in withdrawals.borrow().iter() {
+            let status = withdrawals::to_status(withdrawal);
+            tree.insert(leaf_key(WITHDRAWAL, block_index), encode(&status));
+        }
+    });
+    set_certified_data(&tree);
+    TREE.with(|t| *t.borrow_mut() = tree);
+}
+
+/// Combines two witnesses of the same tree into one revealing what either does.
+fn merge<'a>(a: HashTree<'a>, b: HashTree<'a>) -> HashTree<'a> {
+    match (a, b) {
+        (HashTree::Pruned(_), tree) | (tree, HashTree::Pruned(_)) => tree,
+        (HashTree::Fork(a), HashTree::Fork(b)) => {
+            let (a_left, a_right) = *a;
+            let (b_left, b_right) = *b;
+            fork(merge(a_left, b_left), merge(a_right, b_right))
+        }
+        (HashTree::Labeled(label, a), HashTree::Labeled(_, b)) => {
+            HashTree::Labeled(label, Box::new(merge(*a, *b)))
+        }
+        (tree, _) => tree,
+    }
+}
+
+/// Wraps `data` with the witness `witness` builds from the tree.
+fn certified<T>(
+    data: T,
+    witness: impl for<'a> FnOnce(&'a RbTree<Vec<u8>, Vec<u8>>) -> HashTree<'a>,
+) -> Certified<T> {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness = witness(&tree);
+
+        let mut serializer = serde_cbor::Serializer::new(vec![]);
+        serializer
+            .self_describe()
+            .expect("failed to write the CBOR tag");
+        labeled(LABEL, witness)
+            .serialize(&mut serializer)
+            .expect("failed to serialize the witness");
+
+        Certified {
+            data,
+            certificate: api::data_certificate().map(ByteBuf::from),
+            witness: ByteBuf::from(serializer.into_inner()),
+        }
+    })
+}
+
+/// The block indices a history page was read from, lowest first: from
+/// `start` up to the record before `next`, or to the end when there is no
+/// next page.
+fn span(query: &HistoryQuery, next: Option<u64>) -> (u64, u64) {
+    if history::is_descending(query) {
+        (
+            next.map_or(0, |next| next.saturating_add(1)),
+            query.start.unwrap_or(u64::MAX),
+        )
+    } else {
+        (
+            query.start.unwrap_or(0),
+            next.map_or(u64::MAX, |next| next.saturating_sub(1)),
+        )
+    }
+}
+
+/// Witness of a page of `kind` records. Without a principal it reveals every
+/// record in the span of the page, along with the keys just outside it, so
+/// the absence of others is proven too. With one, it reveals the keys of the
+/// principal's index over the span and the returned records.
+fn page_witness<'a>(
+    tree: &'a RbTree<Vec<u8>, Vec<u8>>,
+    kind: &[u8],
+    query: &HistoryQuery,
+    next: Option<u64>,
+    block_indices: Vec<u64>,
+) -> HashTree<'a> {
+    let (first, last) = span(query, next);
+    match query.principal {
+        None => tree.value_range(&leaf_key(kind, first), &leaf_key(kind, last)),
+        Some(owner) => block_indices
+            .into_iter()
+            .map(|block_index| tree.witness(&leaf_key(kind, block_index)))
+            .fold(
+                tree.key_range(
+                    &index_key(&owner, kind, first),
+                    &index_key(&owner, kind, last),
+                ),
+                merge,
+            ),
+    }
+}
+
+pub fn mints(
+    query: &HistoryQuery,
+    page: HistoryPage<MintRecord>,
+) -> Certified<HistoryPage<MintRecord>> {
+    let next = page.next;
+    let block_indices = page.records.iter().map(|r| r.block_index).collect();
+    certified(page, |tree| {
+        page_witness(tree, MINT, query, next, block_indices)
+    })
+}
+
+pub fn withdraws(
+    query: &HistoryQuery,
+    page: HistoryPage<WithdrawRecord>,
+) -> Certified<HistoryPage<WithdrawRecord>> {
+    let next = page.next;
+    let block_indices = page.records.iter().map(|r| r.block_index).collect();
+    certified(page, |tree| {
+        page_witness(tree, WITHDRAW, query, next, block_indices)
+    })
+}
+
+pub fn withdrawal_status(
+    block_index: u64,
+    status: Option<WithdrawalStatus>,
+) -> Certified<Option<WithdrawalStatus>> {
+    certified(status, |tree| {
+        tree.witness(&leaf_key(WITHDRAWAL, block_index))
+    })
+}
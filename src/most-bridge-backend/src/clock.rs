@@ -0,0 +1,24 @@
+#[cfg(test)]
+use std::cell::Cell;
+
+#[cfg(test)]
+thread_local! {
+    static NOW: Cell<u64> = Cell::new(0);
+}
+
+/// The current time in nanoseconds since the epoch. Outside a canister there
+/// is no system time, so tests set it with `set`.
+#[cfg(not(test))]
+pub fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(test)]
+pub fn now() -> u64 {
+    NOW.with(|now| now.get())
+}
+
+#[cfg(test)]
+pub fn set(now: u64) {
+    NOW.with(|cell| cell.set(now));
+}
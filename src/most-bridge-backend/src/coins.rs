@@ -0,0 +1,237 @@
+use crate::constants::{
+    COIN_MAINTENANCE_GAS_BUDGET, COIN_MAINTENANCE_TASK, DUST_COIN_THRESHOLD, GAS_COIN_MIN_BALANCE,
+    GAS_COIN_TARGET_BALANCE, MAX_COIN_PAGES, MAX_INPUT_COINS, MAX_MERGES_PER_RUN,
+    MINTER_SUI_ADDRESS_KEY,
+};
+use crate::guard::TaskGuard;
+use crate::helper::{KeyName, OwnedCoin};
+use crate::logs::INFO;
+use crate::models::{MergeCoinsRequest, SuiCoin};
+use crate::{sui, transactions, COINS};
+use ic_canister_log::log;
+use std::cmp::Reverse;
+
+fn minter_sui_address() -> Result<String, String> {
+    crate::get(MINTER_SUI_ADDRESS_KEY.to_string()).ok_or("Missing minter Sui address".to_string())
+}
+
+fn to_owned_coin(coin: &SuiCoin) -> Option<OwnedCoin> {
+    Some(OwnedCoin {
+        object_id: coin.coin_object_id.clone(),
+        version: coin.version.parse().ok()?,
+        digest: coin.digest.clone(),
+        balance: coin.balance.parse().ok()?,
+    })
+}
+
+/// Replaces the inventory with the SUI coins the minter currently owns.
+pub async fn refresh() -> Result<(), String> {
+    let owner = minter_sui_address()?;
+    let mut owned = vec![];
+    let mut cursor = None;
+
+    for _ in 0..MAX_COIN_PAGES {
+        let page = sui::get_coins(&owner, cursor).await?;
+        owned.extend(page.data.iter().filter_map(to_owned_coin));
+        if !page.has_next_page {
+            break;
+        }
+        cursor = page.next_cursor;
+    }
+
+    COINS.with(|coins| {
+        let mut coins = coins.borrow_mut();
+        let stale: Vec<KeyName> = coins.iter().map(|(object_id, _)| object_id).collect();
+        for object_id in stale {
+            coins.remove(&object_id);
+        }
+        for coin in owned {
+            coins.insert(KeyName(coin.object_id.clone()), coin);
+        }
+    });
+    Ok(())
+}
+
+pub fn list() -> Vec<OwnedCoin> {
+    COINS.with(|coins| coins.borrow().iter().map(|(_, coin)| coin).collect())
+}
+
+/// Coins whose current version is not used by any in-flight signed transaction.
+fn unreserved() -> Vec<OwnedCoin> {
+    list()
+        .into_iter()
+        .filter(|coin| !transactions::is_reserved(&coin.object_id, coin.version))
+        .collect()
+}
+
+/// Picks the smallest coin that can still pay for gas, so the larger coins stay
+/// available for payments.
+pub fn select_gas_coin(coins: &[OwnedCoin]) -> Option<OwnedCoin> {
+    coins
+        .iter()
+        .filter(|coin| coin.balance >= GAS_COIN_MIN_BALANCE)
+        .min_by_key(|coin| coin.balance)
+        .cloned()
+}
+
+/// Picks a gas coin and the payment coins covering `amount`, largest first.
+pub fn select_withdrawal_coins(amount: u64) -> Result<(OwnedCoin, Vec<String>), String> {
+    let mut coins = unreserved();
+    let gas = select_gas_coin(&coins).ok_or("No gas coin available")?;
+    coins.retain(|coin| coin.object_id != gas.object_id);
+    coins.sort_by_key(|coin| Reverse(coin.balance));
+
+    let mut selected = vec![];
+    let mut available = 0u64;
+    for coin in coins.into_iter().take(MAX_INPUT_COINS as usize) {
+        if available >= amount {
+            break;
+        }
+        available = available.saturating_add(coin.balance);
+        selected.push(coin.object_id);
+    }
+
+    if available < amount {
+        return Err(format!(
+            "Not enough SUI to pay out withdrawals: available {available}, required {amount}"
+        ));
+    }
+    Ok((gas, selected))
+}
+
+/// Plans merging the smallest dust coin into the largest coin that is not used for gas.
+fn plan_merge(coins: &[OwnedCoin], gas: &OwnedCoin) -> Option<MergeCoinsRequest> {
+    let mut candidates: Vec<&OwnedCoin> = coins
+        .iter()
+        .filter(|coin| coin.object_id != gas.object_id)
+        .collect();
+    candidates.sort_by_key(|coin| Reverse(coin.balance));
+
+    let primary = candidates.first()?;
+    let dust = candidates
+        .iter()
+        .skip(1)
+        .rev()
+        .find(|coin| coin.balance < DUST_COIN_THRESHOLD)?;
+
+    Some(MergeCoinsRequest {
+        primary_coin: primary.object_id.clone(),
+        secundary_coin: dust.object_id.clone(),
+    })
+}
+
+/// Keeps a spare gas coin around and merges dust coins.
+pub async fn maintain() {
+    let _guard = match TaskGuard::new(COIN_MAINTENANCE_TASK) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    if let Err(e) = maintain_coins().await {
+        log!(INFO, "Coin maintenance failed: {e}");
+    }
+}
+
+async fn maintain_coins() -> Result<(), String> {
+    let signer = minter_sui_address()?;
+    for (_, result) in transactions::retry_outstanding(COIN_MAINTENANCE_TASK).await {
+        match result {
+            Ok(executed) => {
+                if let Some(e) = executed.failure() {
+                    log!(
+                        INFO,
+                        "Coin maintenance transaction {} failed: {e}",
+                        executed.digest
+                    );
+                }
+            }
+            Err(e) => log!(
+                INFO,
+                "Signed coin maintenance transaction is still outstanding: {e}"
+            ),
+        }
+    }
+    refresh().await?;
+    ensure_gas_coin(&signer).await?;
+
+    for _ in 0..MAX_MERGES_PER_RUN {
+        let coins = unreserved();
+        let gas = match select_gas_coin(&coins) {
+            Some(gas) => gas,
+            None => break,
+        };
+        let merge = match plan_merge(&coins, &gas) {
+            Some(merge) => merge,
+            None => break,
+        };
+
+        let tx = sui::merge_coins(
+            &signer,
+            merge.primary_coin.clone(),
+            merge.secundary_coin.clone(),
+            gas.object_id,
+            COIN_MAINTENANCE_GAS_BUDGET,
+        )
+        .await?;
+        let executed = transactions::sign_and_execute(COIN_MAINTENANCE_TASK, tx, vec![]).await?;
+        if let Some(e) = executed.failure() {
+            return Err(format!("Merge {} failed: {e}", executed.digest));
+        }
+        log!(
+            INFO,
+            "Merged coin {} into {} ({})",
+            merge.secundary_coin,
+            merge.primary_coin,
+            executed.digest
+        );
+        refresh().await?;
+    }
+    Ok(())
+}
+
+/// Splits a dedicated gas coin off the largest coin when no coin besides the
+/// one used for gas could pay out withdrawals.
+async fn ensure_gas_coin(signer: &str) -> Result<(), String> {
+    let coins = unreserved();
+    let gas_candidates = coins
+        .iter()
+        .filter(|coin| coin.balance >= GAS_COIN_MIN_BALANCE)
+        .count();
+    if gas_candidates >= 2 {
+        return Ok(());
+    }
+
+    let largest = coins
+        .iter()
+        .max_by_key(|coin| coin.balance)
+        .ok_or("The minter owns no SUI coins")?;
+    if largest.balance < GAS_COIN_TARGET_BALANCE + GAS_COIN_MIN_BALANCE {
+        log!(
+            INFO,
+            "Largest coin {} ({}) is too small to split off a gas coin",
+            largest.object_id,
+            largest.balance
+        );
+        return Ok(());
+    }
+
+    let tx = sui::pay_sui(
+        signer,
+        vec![largest.object_id.clone()],
+        vec![signer.to_string()],
+        vec![GAS_COIN_TARGET_BALANCE],
+        COIN_MAINTENANCE_GAS_BUDGET,
+    )
+    .await?;
+    let executed = transactions::sign_and_execute(COIN_MAINTENANCE_TASK, tx, vec![]).await?;
+    if let Some(e) = executed.failure() {
+        return Err(format!("Gas coin split {} failed: {e}", executed.digest));
+    }
+    log!(
+        INFO,
+        "Split a gas coin off {} ({})",
+        largest.object_id,
+        executed.digest
+    );
+    refresh().await
+}
@@ -1,3 +1,4 @@
+use crate::helper::WithdrawalState;
 use candid::{CandidType, Principal};
 use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::NumTokens;
@@ -9,18 +10,6 @@ pub struct TransferArgs {
     to_account: Account,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Context {
-    pub bucket_start_time_index: usize,
-    pub closing_price_index: usize,
-}
-
-#[derive(Deserialize, Serialize, Debug)]
-pub struct TxDigestResponse {
-    pub digest: String,
-    pub tx_bytes: String,
-}
-
 #[derive(CandidType, Serialize, Debug)]
 pub struct PublicKeyReply {
     pub public_key_hex: String,
@@ -28,7 +17,20 @@ pub struct PublicKeyReply {
 
 #[derive(CandidType, Serialize, Debug)]
 pub struct WithdrawResponse {
-    pub tx_digest: String,
+    pub block_index: String,
+}
+
+#[derive(CandidType, Debug)]
+pub struct WithdrawalStatus {
+    pub block_index: u64,
+    pub from: Principal,
+    pub recipient: String,
+    pub amount: u64,
+    pub created_at: u64,
+    pub state: WithdrawalState,
+    pub tx_digest: Option<String>,
+    pub explorer_url: Option<String>,
+    pub error: Option<String>,
 }
 
 #[derive(CandidType, Serialize, Debug)]
@@ -77,25 +79,96 @@ pub struct EcdsaKeyId {
     pub name: String,
 }
 
+#[derive(CandidType, Serialize, Debug)]
+pub struct SchnorrPublicKey {
+    pub canister_id: Option<CanisterId>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: SchnorrKeyId,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SchnorrPublicKeyReply {
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Debug)]
+pub struct SignWithSchnorr {
+    pub message: Vec<u8>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: SchnorrKeyId,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SignWithSchnorrReply {
+    pub signature: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Debug, Clone)]
+pub enum SchnorrAlgorithm {
+    #[serde(rename = "ed25519")]
+    Ed25519,
+}
+
+#[derive(CandidType, Serialize, Debug, Clone)]
+pub struct SchnorrKeyId {
+    pub algorithm: SchnorrAlgorithm,
+    pub name: String,
+}
+
+/// Key scheme backing the minter's Sui address.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    Ed25519,
+    Secp256k1,
+}
+
+impl SignatureScheme {
+    /// Sui signature scheme flag, prepended to the public key and the signature.
+    pub fn flag(&self) -> u8 {
+        match self {
+            Self::Ed25519 => 0x0,
+            Self::Secp256k1 => 0x1,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EcdsaKeyIds {
-    #[allow(unused)]
     TestKeyLocalDevelopment,
-    #[allow(unused)]
     TestKey1,
-    #[allow(unused)]
     ProductionKey1,
 }
 
+/// The threshold key the minter signs with: the key name and the scheme,
+/// which selects the curve (secp256k1 ECDSA or ed25519 Schnorr).
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyConfig {
+    pub key_id: EcdsaKeyIds,
+    pub scheme: SignatureScheme,
+}
+
 impl EcdsaKeyIds {
+    fn name(&self) -> String {
+        match self {
+            Self::TestKeyLocalDevelopment => "dfx_test_key",
+            Self::TestKey1 => "test_key_1",
+            Self::ProductionKey1 => "key_1",
+        }
+        .to_string()
+    }
+
     pub fn to_key_id(&self) -> EcdsaKeyId {
         EcdsaKeyId {
             curve: EcdsaCurve::Secp256k1,
-            name: match self {
-                Self::TestKeyLocalDevelopment => "dfx_test_key",
-                Self::TestKey1 => "test_key_1",
-                Self::ProductionKey1 => "key_1",
-            }
-            .to_string(),
+            name: self.name(),
+        }
+    }
+
+    pub fn to_schnorr_key_id(&self) -> SchnorrKeyId {
+        SchnorrKeyId {
+            algorithm: SchnorrAlgorithm::Ed25519,
+            name: self.name(),
         }
     }
 }
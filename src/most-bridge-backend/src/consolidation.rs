@@ -0,0 +1,311 @@
+use crate::common::KeyConfig;
+use crate::constants::{
+    CONSOLIDATION_TASK, MAX_INPUT_COINS, MINTER_SUI_ADDRESS_KEY, SWEEPS_PAGE_SIZE,
+    SWEEP_GAS_BUDGET, SWEEP_MIN_BALANCE,
+};
+use crate::events::{self, EventType};
+use crate::guard::TaskGuard;
+use crate::helper::{ControlledAddress, KeyName, SweepRecord, SweepStatus};
+use crate::logs::INFO;
+use crate::models::ExecuteTxBlockResponseResult;
+use crate::{
+    clock, deposits, history, sui, transactions, CONTROLLED_ADDRESSES, DEPOSIT_ADDRESSES, SWEEPS,
+    SWEEP_DIGESTS,
+};
+use ic_canister_log::log;
+
+/// Registers a derivation path of the minter key whose funds are swept into
+/// the main address, and returns the Sui address it controls.
+pub async fn register(derivation_path: Vec<Vec<u8>>, label: String) -> Result<String, String> {
+    if derivation_path.is_empty() {
+        return Err("The empty derivation path is the main address".to_string());
+    }
+    let key = crate::key_config();
+    let public_key = crate::derive_public_key(key, derivation_path.clone()).await?;
+    let address = sui::address_from_public_key(key.scheme, &public_key.public_key)?;
+
+    add(ControlledAddress {
+        address: address.clone(),
+        key,
+        derivation_path,
+        label,
+        registered_at: clock::now(),
+    });
+    Ok(address)
+}
+
+/// Stores an address derived by `register`.
+pub fn add(controlled: ControlledAddress) {
+    let address = controlled.address.clone();
+    events::record(EventType::RegisteredDerivationPath(controlled.clone()));
+    CONTROLLED_ADDRESSES.with(|addresses| {
+        addresses
+            .borrow_mut()
+            .insert(KeyName(address.clone()), controlled)
+    });
+    log!(INFO, "Registered controlled address {address}");
+}
+
+pub fn unregister(address: String) -> Result<(), String> {
+    CONTROLLED_ADDRESSES
+        .with(|addresses| addresses.borrow_mut().remove(&KeyName(address.clone())))
+        .ok_or(format!("Unknown controlled address {address}"))?;
+    events::record(EventType::UnregisteredDerivationPath { address });
+    Ok(())
+}
+
+pub fn list() -> Vec<ControlledAddress> {
+    CONTROLLED_ADDRESSES.with(|addresses| {
+        addresses
+            .borrow()
+            .iter()
+            .map(|(_, address)| address)
+            .collect()
+    })
+}
+
+struct SweepSource {
+    address: String,
+    key: KeyConfig,
+    derivation_path: Vec<Vec<u8>>,
+    /// Set for deposit addresses.
+    deposit: Option<KeyName>,
+}
+
+/// Registered addresses and the deposit addresses credited since they were
+/// last found empty, which are controlled by the minter key as well.
+fn sweep_sources() -> Vec<SweepSource> {
+    let registered = list().into_iter().map(|address| SweepSource {
+        address: address.address,
+        key: address.key,
+        derivation_path: address.derivation_path,
+        deposit: None,
+    });
+    let deposits: Vec<SweepSource> = DEPOSIT_ADDRESSES.with(|deposits| {
+        deposits
+            .borrow()
+            .iter()
+            .filter(|(_, deposit)| deposit.holds_balance())
+            .map(|(key, deposit)| SweepSource {
+                address: deposit.address,
+                key: deposit.key,
+                derivation_path: deposit.derivation_path,
+                deposit: Some(key),
+            })
+            .collect()
+    });
+    registered.chain(deposits).collect()
+}
+
+/// Moves the SUI held by every minter-controlled address into the main address.
+pub async fn consolidate() {
+    let _guard = match TaskGuard::new(CONSOLIDATION_TASK) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    for (tx, result) in transactions::retry_outstanding(CONSOLIDATION_TASK).await {
+        if let Ok(digest) = sui::transaction_digest(&tx.tx_bytes) {
+            record_outcome(&digest, result);
+        }
+    }
+
+    let destination = match crate::get(MINTER_SUI_ADDRESS_KEY.to_string()) {
+        Some(destination) => destination,
+        None => return,
+    };
+    for source in sweep_sources() {
+        let SweepSource {
+            address,
+            key,
+            derivation_path,
+            deposit,
+        } = source;
+        let checked_at = clock::now();
+        match sweep(&address, key, derivation_path, &destination).await {
+            // Deposit addresses aren't checked again until a deposit is credited
+            // after `checked_at`.
+            Ok(true) => {
+                if let Some(deposit) = deposit {
+                    deposits::update(&deposit, |d| d.swept_at = Some(checked_at));
+                }
+            }
+            Ok(false) => {}
+            Err(e) => log!(INFO, "Failed to sweep {address}: {e}"),
+        }
+    }
+}
+
+/// Sweeps `address` and returns whether it holds nothing worth sweeping,
+/// counting coins an outstanding transaction still reserves.
+async fn sweep(
+    address: &str,
+    key: KeyConfig,
+    derivation_path: Vec<Vec<u8>>,
+    destination: &str,
+) -> Result<bool, String> {
+    let page = sui::get_coins(address, None).await?;
+    let held = page
+        .data
+        .iter()
+        .filter_map(|coin| coin.balance.parse::<u64>().ok())
+        .fold(0u64, |total, balance| total.saturating_add(balance));
+    if held < SWEEP_MIN_BALANCE.saturating_add(SWEEP_GAS_BUDGET) {
+        return Ok(true);
+    }
+
+    let coins: Vec<(String, u64)> = page
+        .data
+        .iter()
+        .filter_map(|coin| {
+            let version = coin.version.parse::<u64>().ok()?;
+            if transactions::is_reserved(&coin.coin_object_id, version) {
+                return None;
+            }
+            Some((
+                coin.coin_object_id.clone(),
+                coin.balance.parse::<u64>().ok()?,
+            ))
+        })
+        .take(MAX_INPUT_COINS as usize)
+        .collect();
+    let balance = coins
+        .iter()
+        .fold(0u64, |total, (_, balance)| total.saturating_add(*balance));
+
+    // Sweeping costs gas, so small balances are left until they grow.
+    if balance < SWEEP_MIN_BALANCE.saturating_add(SWEEP_GAS_BUDGET) {
+        return Ok(false);
+    }
+
+    let input_coins: Vec<String> = coins.into_iter().map(|(object_id, _)| object_id).collect();
+    let tx = sui::pay_all_sui(
+        address,
+        input_coins.clone(),
+        destination.to_string(),
+        SWEEP_GAS_BUDGET,
+    )
+    .await?;
+    let tx_digest = sui::transaction_digest(&tx.tx_bytes)?;
+
+    start_sweep(address, destination, balance, input_coins, &tx_digest);
+
+    let result = transactions::sign_and_execute_with_path(
+        CONSOLIDATION_TASK,
+        tx,
+        vec![],
+        key,
+        derivation_path,
+    )
+    .await;
+    record_outcome(&tx_digest, result);
+    Ok(false)
+}
+
+/// Records a sweep whose transaction is about to be signed.
+pub fn start_sweep(from: &str, to: &str, balance: u64, coins: Vec<String>, tx_digest: &str) {
+    let now = clock::now();
+    save_sweep(SweepRecord {
+        id: next_sweep_id(),
+        from: from.to_string(),
+        to: to.to_string(),
+        balance,
+        coins,
+        tx_digest: tx_digest.to_string(),
+        status: SweepStatus::Submitted,
+        error: None,
+        created_at: now,
+        updated_at: now,
+    });
+}
+
+fn save_sweep(sweep: SweepRecord) {
+    events::record(EventType::Swept(sweep.clone()));
+    SWEEP_DIGESTS.with(|index| {
+        index
+            .borrow_mut()
+            .insert(digest_key(&sweep.tx_digest, sweep.id), ())
+    });
+    SWEEPS.with(|sweeps| sweeps.borrow_mut().insert(sweep.id, sweep));
+}
+
+fn digest_key(tx_digest: &str, id: u64) -> KeyName {
+    // Zero padded so that the sweeps of one transaction sort by id.
+    KeyName(format!("{tx_digest}:{id:020}"))
+}
+
+/// Indexes the sweeps recorded before the index existed.
+pub fn backfill_index() {
+    let already_indexed = SWEEP_DIGESTS.with(|index| !index.borrow().is_empty());
+    if already_indexed {
+        return;
+    }
+    SWEEPS.with(|sweeps| {
+        SWEEP_DIGESTS.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, sweep) in sweeps.borrow().iter() {
+                index.insert(digest_key(&sweep.tx_digest, id), ());
+            }
+        })
+    });
+}
+
+fn next_sweep_id() -> u64 {
+    SWEEPS.with(|sweeps| sweeps.borrow().last_key_value().map_or(0, |(id, _)| id + 1))
+}
+
+pub fn record_outcome(tx_digest: &str, result: Result<ExecuteTxBlockResponseResult, String>) {
+    let (status, error) = match &result {
+        Ok(executed) => match executed.failure() {
+            None => (SweepStatus::Succeeded, None),
+            Some(e) => (SweepStatus::Failed, Some(e)),
+        },
+        // The signed transaction is retried on the next run until it expires.
+        Err(e) => (SweepStatus::Submitted, Some(e.clone())),
+    };
+
+    let prefix = format!("{tx_digest}:");
+    let ids: Vec<u64> = SWEEP_DIGESTS.with(|index| {
+        index
+            .borrow()
+            .range(digest_key(tx_digest, 0)..)
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+            .filter_map(|(key, _)| key.0.rsplit(':').next()?.parse::<u64>().ok())
+            .collect()
+    });
+    let found: Vec<SweepRecord> = SWEEPS.with(|sweeps| {
+        ids.iter()
+            .filter_map(|id| sweeps.borrow().get(id))
+            .collect()
+    });
+    for mut sweep in found {
+        sweep.status = status;
+        sweep.error = error.clone();
+        sweep.updated_at = clock::now();
+        log!(
+            INFO,
+            "Sweep {} from {} is {:?} ({})",
+            sweep.id,
+            sweep.from,
+            sweep.status,
+            tx_digest
+        );
+        save_sweep(sweep);
+    }
+}
+
+/// Sweep records, newest first. Only the records up to the end of the page
+/// are read.
+pub fn get_sweeps(page: u64) -> Vec<SweepRecord> {
+    let skip = usize::try_from(page)
+        .unwrap_or(usize::MAX)
+        .saturating_mul(SWEEPS_PAGE_SIZE);
+    SWEEPS.with(|sweeps| {
+        let sweeps = sweeps.borrow();
+        history::descending(&sweeps, None)
+            .skip(skip)
+            .take(SWEEPS_PAGE_SIZE)
+            .map(|(_, sweep)| sweep)
+            .collect()
+    })
+}
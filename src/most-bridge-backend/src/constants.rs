@@ -1,15 +1,115 @@
 use std::time::Duration;
 
 pub const QUERY_SUI_EVENTS_INTERVAL: Duration = Duration::from_secs(3 * 60);
+pub const FLUSH_WITHDRAWALS_INTERVAL: Duration = Duration::from_secs(60);
+pub const COIN_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+pub const SCAN_DEPOSITS_INTERVAL: Duration = Duration::from_secs(3 * 60);
+pub const CONSOLIDATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Transient outcall failures are retried up to OUTCALL_MAX_ATTEMPTS times in
+// total, waiting twice as long before each retry, up to OUTCALL_MAX_BACKOFF.
+pub const OUTCALL_MAX_ATTEMPTS: u32 = 3;
+pub const OUTCALL_INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+pub const OUTCALL_MAX_BACKOFF: Duration = Duration::from_secs(16);
+// The id of the last deposit event minted, its transaction digest and sequence number.
 pub const PROCESSED_TX_DIGEST_KEY: &str = "txDigest";
+pub const PROCESSED_EVENT_SEQ_KEY: &str = "eventSeq";
+// Deposit events minted per run at most.
+pub const EVENTS_PAGE_SIZE: u64 = 50;
 pub const LEDGER_CANISTER_ID_KEY: &str = "ledger_canister_id_key";
 pub const LOCAL_MGMT_PRINCIPAL_ID_KEY: &str = "local_mgmt_principal_id_key";
-pub const API_URL_KEY: &str = "api_url_key";
-pub const TX_DIGEST_URL_KEY: &str = "tx_digest_url_key";
 pub const IS_LOCAL_KEY: &str = "is_local_key";
 pub const MINTER_ADDRESS_KEY: &str = "minter_address_key";
-pub const QUERY_EVENTS_URL_KEY: &str = "query_events_url_key";
-pub const EXECUTE_TX_BLOCK_URL_KEY: &str = "execute_tx_block_url_key";
+pub const DEPOSIT_EVENT_TYPE_KEY: &str = "deposit_event_type_key";
+pub const MINTER_SUI_ADDRESS_KEY: &str = "minter_sui_address_key";
+pub const KEY_CONFIG_KEY: &str = "key_config_key";
+pub const PUBLIC_KEY_KEY: &str = "public_key_key";
+pub const PUBLIC_KEY_CONFIG_KEY: &str = "public_key_config_key";
+pub const SUBNET_SIZE_KEY: &str = "subnet_size_key";
+pub const NEW_DEPOSIT_ADDRESSES_KEY: &str = "new_deposit_addresses_key";
+pub const DEPOSIT_SCAN_CURSOR_KEY: &str = "deposit_scan_cursor_key";
+// Where minters from before the provider registry kept their single RPC URL.
+pub const LEGACY_API_URL_KEY: &str = "api_url_key";
+
+// Name of the provider `api_url` is registered under on install.
+pub const DEFAULT_PROVIDER_NAME: &str = "default";
+// Provider health goes from 0 to PROVIDER_MAX_HEALTH. Failures cost more than
+// successes earn, so a flaky provider quickly falls behind the others.
+pub const PROVIDER_INITIAL_HEALTH: u32 = 50;
+pub const PROVIDER_MAX_HEALTH: u32 = 100;
+pub const PROVIDER_SUCCESS_REWARD: u32 = 5;
+pub const PROVIDER_FAILURE_PENALTY: u32 = 20;
+// Followed by the endpoint name, holds the response size estimate learned for it.
+pub const RESPONSE_SIZE_ESTIMATE_KEY_PREFIX: &str = "response_size_estimate_";
+
+pub const SUI_COIN_TYPE: &str = "0x2::sui::SUI";
+// Intent prefix for a Sui transaction: scope TransactionData, version V0, app id Sui.
+pub const SUI_TX_INTENT: [u8; 3] = [0, 0, 0];
+
+// A queued withdrawal batch is flushed as soon as it reaches this many entries,
+// which is also the maximum number of recipients in a single transaction.
+pub const MAX_WITHDRAWAL_BATCH_SIZE: u64 = 50;
+pub const WITHDRAWAL_GAS_BUDGET: u64 = 50_000_000;
+// Withdrawals whose transaction failed on chain this many times are reimbursed.
+pub const MAX_WITHDRAWAL_ATTEMPTS: u32 = 3;
+pub const MAX_INPUT_COINS: u64 = 50;
+pub const WITHDRAWALS_PAGE_SIZE: usize = 20;
+// Default and maximum number of records in one page of mint or withdraw history.
+pub const DEFAULT_HISTORY_LIMIT: u64 = 50;
+pub const MAX_HISTORY_LIMIT: u64 = 500;
+// Records one history query looks at, matching the filters or not. A page that
+// stops here is cut short and its `next` resumes the scan. The certified pages
+// reveal every record or index entry looked at, so this also bounds their witness.
+pub const MAX_HISTORY_SCAN: usize = 1_000;
+// Event senders that aren't valid Sui addresses are kept cut to the length of one.
+pub const MAX_RAW_SENDER_CHARS: usize = 66;
+pub const MAX_EVENTS_PAGE_SIZE: u64 = 1000;
+pub const MAX_COIN_PAGES: usize = 10;
+
+// The minter keeps one coin of at least GAS_COIN_MIN_BALANCE aside to pay for gas.
+// When no such coin is left, GAS_COIN_TARGET_BALANCE is split off the largest coin.
+pub const GAS_COIN_MIN_BALANCE: u64 = 100_000_000;
+pub const GAS_COIN_TARGET_BALANCE: u64 = 1_000_000_000;
+// Coins below this balance are merged into the largest coin, a few per maintenance run.
+pub const DUST_COIN_THRESHOLD: u64 = 10_000_000;
+pub const MAX_MERGES_PER_RUN: usize = 5;
+pub const COIN_MAINTENANCE_GAS_BUDGET: u64 = 10_000_000;
+
+pub const WITHDRAWALS_TASK: &str = "withdrawals";
+pub const COIN_MAINTENANCE_TASK: &str = "coin_maintenance";
+pub const DEPOSITS_TASK: &str = "deposits";
+pub const CONSOLIDATION_TASK: &str = "consolidation";
+pub const KEY_MIGRATION_TASK: &str = "key_migration";
+
+// First derivation path component of per-account deposit addresses.
+pub const DEPOSIT_DERIVATION_DOMAIN: &[u8] = b"deposit";
+// Transactions fetched per deposit address and scan.
+pub const DEPOSITS_PAGE_SIZE: u64 = 50;
+// Each deposit address costs outcalls to scan and sweep, so at most
+// MAX_NEW_DEPOSIT_ADDRESSES are created per NEW_DEPOSIT_ADDRESSES_WINDOW_NANOS, at
+// most MAX_NEW_DEPOSIT_ADDRESSES_PER_PRINCIPAL of them for one principal, and an
+// address is only scanned for DEPOSIT_ADDRESS_ACTIVE_NANOS after it was last
+// requested or credited. Requests refresh an address at most once per
+// DEPOSIT_ADDRESS_REFRESH_NANOS.
+pub const MAX_NEW_DEPOSIT_ADDRESSES: u64 = 100;
+pub const MAX_NEW_DEPOSIT_ADDRESSES_PER_PRINCIPAL: u64 = 5;
+pub const NEW_DEPOSIT_ADDRESSES_WINDOW_NANOS: u64 = 60 * 60 * 1_000_000_000;
+pub const DEPOSIT_ADDRESS_REFRESH_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const DEPOSIT_ADDRESS_ACTIVE_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+// A scan queries at most MAX_DEPOSIT_SCANS_PER_RUN active addresses out of at most
+// MAX_DEPOSIT_ADDRESSES_CHECKED_PER_RUN, and the next scan continues after the last
+// address checked.
+pub const MAX_DEPOSIT_SCANS_PER_RUN: usize = 20;
+pub const MAX_DEPOSIT_ADDRESSES_CHECKED_PER_RUN: usize = 1_000;
+
+// Minter-controlled addresses are swept into the main address once they hold
+// SWEEP_MIN_BALANCE on top of the gas the sweep costs.
+pub const SWEEP_MIN_BALANCE: u64 = 100_000_000;
+pub const SWEEP_GAS_BUDGET: u64 = 10_000_000;
+pub const SWEEPS_PAGE_SIZE: usize = 20;
+// Owned object locks taken by a signed transaction are released at the end of the
+// epoch (at most 24h). A transaction that still can't be executed after that is
+// abandoned, and its objects used again, once one of them has moved on.
+pub const SIGNED_TX_EXPIRY_NANOS: u64 = 25 * 60 * 60 * 1_000_000_000;
 
 // This constant is our approximation of the expected header size.
 // The HTTP standard doesn't define any limit, and many implementations limit
@@ -23,3 +123,7 @@ const HEADER_SIZE_LIMIT: u64 = 2 * 1024;
 const HTTP_MAX_SIZE: u64 = 2_000_000;
 
 pub const MAX_PAYLOAD_SIZE: u64 = HTTP_MAX_SIZE - HEADER_SIZE_LIMIT;
+
+// Number of nodes of the subnet the minter runs on, which HTTP outcalls are
+// priced by. Used unless `subnet_size` is set.
+pub const DEFAULT_SUBNET_SIZE: u128 = 34;
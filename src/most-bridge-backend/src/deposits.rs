@@ -0,0 +1,362 @@
+use crate::common::KeyConfig;
+use crate::constants::{
+    DEPOSITS_PAGE_SIZE, DEPOSITS_TASK, DEPOSIT_ADDRESS_ACTIVE_NANOS, DEPOSIT_ADDRESS_REFRESH_NANOS,
+    DEPOSIT_DERIVATION_DOMAIN, DEPOSIT_SCAN_CURSOR_KEY, LEDGER_CANISTER_ID_KEY,
+    MAX_DEPOSIT_ADDRESSES_CHECKED_PER_RUN, MAX_DEPOSIT_SCANS_PER_RUN, MAX_NEW_DEPOSIT_ADDRESSES,
+    MAX_NEW_DEPOSIT_ADDRESSES_PER_PRINCIPAL, NEW_DEPOSIT_ADDRESSES_KEY,
+    NEW_DEPOSIT_ADDRESSES_WINDOW_NANOS, SUI_COIN_TYPE,
+};
+use crate::events::{self, EventType};
+use crate::guard::TaskGuard;
+use crate::helper::{DepositAddress, KeyName, KeyValue, MintRecord, SuiOrigin};
+use crate::logs::INFO;
+use crate::models::SuiTransactionBlock;
+use crate::{clock, history, sui, CREDITED_DEPOSITS, DEPOSIT_ADDRESSES};
+use candid::{Nat, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::Account;
+use std::ops::Bound;
+
+/// Derivation path of the threshold key controlling the deposit address of `account`.
+pub fn derivation_path(account: &Account) -> Vec<Vec<u8>> {
+    vec![
+        DEPOSIT_DERIVATION_DOMAIN.to_vec(),
+        account.owner.as_slice().to_vec(),
+        account.effective_subaccount().to_vec(),
+    ]
+}
+
+fn account_key(account: &Account) -> KeyName {
+    KeyName(account.to_string())
+}
+
+/// Applies `f` to the stored deposit address under `key`, returning the result.
+/// Tasks reread the address this way after an await instead of writing back a
+/// stale copy.
+pub fn update(key: &KeyName, f: impl FnOnce(&mut DepositAddress)) -> Option<DepositAddress> {
+    DEPOSIT_ADDRESSES.with(|deposits| {
+        let mut deposits = deposits.borrow_mut();
+        let mut deposit = deposits.get(key)?;
+        f(&mut deposit);
+        deposits.insert(key.clone(), deposit.clone());
+        Some(deposit)
+    })
+}
+
+/// Counts a new address for `owner` against the rate limits, or fails if a
+/// limit of the current window is reached. The global window is kept in
+/// stable memory, the per-principal one is read from the owner's addresses.
+fn take_new_address_slot(owner: Principal, now: u64) -> Result<(), String> {
+    if created_since(
+        owner,
+        now.saturating_sub(NEW_DEPOSIT_ADDRESSES_WINDOW_NANOS),
+    ) >= MAX_NEW_DEPOSIT_ADDRESSES_PER_PRINCIPAL
+    {
+        return Err(
+            "Too many new deposit addresses for this principal, try again later".to_string(),
+        );
+    }
+
+    let (mut window_start, mut count): (u64, u64) =
+        crate::get(NEW_DEPOSIT_ADDRESSES_KEY.to_string())
+            .and_then(|window| serde_json::from_str(&window).ok())
+            .unwrap_or_default();
+    if now.saturating_sub(window_start) >= NEW_DEPOSIT_ADDRESSES_WINDOW_NANOS {
+        (window_start, count) = (now, 0);
+    }
+    if count >= MAX_NEW_DEPOSIT_ADDRESSES {
+        return Err("Too many new deposit addresses, try again later".to_string());
+    }
+    crate::insert(
+        NEW_DEPOSIT_ADDRESSES_KEY.to_string(),
+        serde_json::to_string(&(window_start, count + 1)).unwrap(),
+    );
+    Ok(())
+}
+
+/// Number of deposit addresses of `owner` created at or after `since`. The
+/// keys of an owner's accounts all start with its textual principal.
+fn created_since(owner: Principal, since: u64) -> u64 {
+    let prefix = owner.to_text();
+    DEPOSIT_ADDRESSES.with(|deposits| {
+        deposits
+            .borrow()
+            .range(KeyName(prefix.clone())..)
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+            .filter(|(_, deposit)| deposit.owner == owner && deposit.created_at >= since)
+            .count() as u64
+    })
+}
+
+/// Returns the Sui address deposits for `account` are sent to, deriving and
+/// registering it on first use. Handing it out keeps the address scanned for
+/// deposits for DEPOSIT_ADDRESS_ACTIVE_NANOS.
+pub async fn get_or_create_address(account: Account) -> Result<String, String> {
+    let now = clock::now();
+    let key_name = account_key(&account);
+    if let Some(deposit) = DEPOSIT_ADDRESSES.with(|deposits| deposits.borrow().get(&key_name)) {
+        let requested_at = deposit.requested_at.unwrap_or(deposit.created_at);
+        if now.saturating_sub(requested_at) >= DEPOSIT_ADDRESS_REFRESH_NANOS {
+            update(&key_name, |deposit| deposit.requested_at = Some(now));
+        }
+        return Ok(deposit.address);
+    }
+    take_new_address_slot(account.owner, now)?;
+
+    let key = crate::key_config();
+    let derivation_path = derivation_path(&account);
+    let public_key = crate::derive_public_key(key, derivation_path.clone()).await?;
+    let address = sui::address_from_public_key(key.scheme, &public_key.public_key)?;
+    add_address(account, key, derivation_path, address.clone());
+    Ok(address)
+}
+
+/// Stores the address `get_or_create_address` derived for `account`.
+pub fn add_address(
+    account: Account,
+    key: KeyConfig,
+    derivation_path: Vec<Vec<u8>>,
+    address: String,
+) {
+    let now = clock::now();
+    events::record(EventType::CreatedDepositAddress {
+        account,
+        address: address.clone(),
+        key,
+    });
+    DEPOSIT_ADDRESSES.with(|deposits| {
+        deposits.borrow_mut().insert(
+            account_key(&account),
+            DepositAddress {
+                owner: account.owner,
+                subaccount: account.subaccount.map(|s| s.to_vec()),
+                address: address.clone(),
+                key,
+                derivation_path,
+                cursor: None,
+                created_at: now,
+                requested_at: Some(now),
+                credited_at: None,
+                swept_at: Some(now),
+                pending_credit: None,
+            },
+        )
+    });
+    log!(INFO, "Registered deposit address {address} for {account}");
+}
+
+/// Credits the SUI sent to the active deposit addresses since the last scan.
+pub async fn scan() {
+    let _guard = match TaskGuard::new(DEPOSITS_TASK) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    let deposits = next_active_batch(clock::now());
+    for (key, deposit) in deposits {
+        if let Err(e) = scan_address(key, deposit).await {
+            log!(INFO, "Failed to scan deposit address: {e}");
+        }
+    }
+}
+
+/// Takes the next active addresses to scan, starting after the address the
+/// previous scan stopped at and wrapping around at the end, and stores where
+/// this one stops.
+fn next_active_batch(now: u64) -> Vec<(KeyName, DepositAddress)> {
+    let start = crate::get(DEPOSIT_SCAN_CURSOR_KEY.to_string())
+        .filter(|cursor| !cursor.is_empty())
+        .map(KeyName);
+    let mut batch = vec![];
+    let mut stopped_at = None;
+    DEPOSIT_ADDRESSES.with(|deposits| {
+        let deposits = deposits.borrow();
+        let lower = match start {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        for (checked, (key, deposit)) in deposits.range((lower, Bound::Unbounded)).enumerate() {
+            if deposit.is_active(now, DEPOSIT_ADDRESS_ACTIVE_NANOS) {
+                batch.push((key.clone(), deposit));
+            }
+            if batch.len() >= MAX_DEPOSIT_SCANS_PER_RUN
+                || checked + 1 >= MAX_DEPOSIT_ADDRESSES_CHECKED_PER_RUN
+            {
+                stopped_at = Some(key);
+                break;
+            }
+        }
+    });
+    crate::insert(
+        DEPOSIT_SCAN_CURSOR_KEY.to_string(),
+        stopped_at.map(|key| key.0).unwrap_or_default(),
+    );
+    batch
+}
+
+async fn scan_address(key: KeyName, deposit: DepositAddress) -> Result<(), String> {
+    let page =
+        sui::query_transactions_to(&deposit.address, deposit.cursor.clone(), DEPOSITS_PAGE_SIZE)
+            .await?;
+
+    for tx in &page.data {
+        let amount = received_amount(tx, &deposit.address);
+        if amount == 0 || is_credited(&tx.digest, &deposit.address) {
+            continue;
+        }
+        // The cursor only moves past a page once all of its deposits are
+        // credited, so a failed transfer is retried on the next scan.
+        credit(&key, &deposit, tx, amount).await?;
+        update(&key, |deposit| {
+            deposit.credited_at = Some(clock::now());
+            deposit.pending_credit = None;
+        });
+    }
+
+    if let Some(cursor) = page.next_cursor {
+        update(&key, |deposit| deposit.cursor = Some(cursor));
+    }
+    Ok(())
+}
+
+/// Sum of the positive SUI balance changes of `address` in `tx`.
+fn received_amount(tx: &SuiTransactionBlock, address: &str) -> u64 {
+    tx.balance_changes
+        .iter()
+        .flatten()
+        .filter(|change| change.coin_type == SUI_COIN_TYPE)
+        .filter(|change| {
+            change
+                .owner
+                .get("AddressOwner")
+                .and_then(|owner| owner.as_str())
+                .map_or(false, |owner| sui::same_address(owner, address))
+        })
+        .filter_map(|change| change.amount.parse::<i128>().ok())
+        .filter(|amount| *amount > 0)
+        .map(|amount| u64::try_from(amount).unwrap_or(u64::MAX))
+        .fold(0u64, |total, amount| total.saturating_add(amount))
+}
+
+fn credited_key(tx_digest: &str, address: &str) -> KeyName {
+    KeyName(format!("{}:{}", tx_digest, address))
+}
+
+fn is_credited(tx_digest: &str, address: &str) -> bool {
+    CREDITED_DEPOSITS.with(|credited| {
+        credited
+            .borrow()
+            .contains_key(&credited_key(tx_digest, address))
+    })
+}
+
+/// The `created_at_time` of the credit of `tx_digest`, stored on the deposit
+/// address before the first transfer is attempted.
+fn credit_created_at(key: &KeyName, tx_digest: &str) -> u64 {
+    let now = clock::now();
+    update(key, |deposit| match &deposit.pending_credit {
+        Some((digest, _)) if digest == tx_digest => {}
+        _ => deposit.pending_credit = Some((tx_digest.to_string(), now)),
+    })
+    .and_then(|deposit| deposit.pending_credit)
+    .map_or(now, |(_, created_at)| created_at)
+}
+
+/// Mints `amount` for the deposit in `tx`. The transfer has a fixed
+/// `created_at_time` and a memo holding the digest, so a retry after an
+/// unclear outcome can't credit it twice.
+async fn credit(
+    key: &KeyName,
+    deposit: &DepositAddress,
+    tx: &SuiTransactionBlock,
+    amount: u64,
+) -> Result<(), String> {
+    use icrc_ledger_client::{CdkRuntime, ICRC1Client};
+    use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+    use serde_bytes::ByteBuf;
+
+    let tx_digest = tx.digest.as_str();
+    let memo = bs58::decode(tx_digest)
+        .into_vec()
+        .map_err(|e| format!("Invalid deposit digest {tx_digest}: {e}"))?;
+    let ledger_canister_id = crate::get(LEDGER_CANISTER_ID_KEY.to_string())
+        .and_then(|id| Principal::from_text(id).ok())
+        .ok_or("Missing ledger canister id")?;
+    let client = ICRC1Client {
+        runtime: CdkRuntime,
+        ledger_canister_id,
+    };
+
+    let to = Account {
+        owner: deposit.owner,
+        subaccount: deposit
+            .subaccount
+            .as_ref()
+            .and_then(|s| s.as_slice().try_into().ok()),
+    };
+    events::record(EventType::ReceivedDeposit {
+        tx_digest: tx_digest.to_string(),
+        address: deposit.address.clone(),
+        amount,
+        to,
+    });
+    let block_index = match client
+        .transfer(TransferArg {
+            from_subaccount: None,
+            to,
+            fee: None,
+            created_at_time: Some(credit_created_at(key, tx_digest)),
+            memo: Some(Memo(ByteBuf::from(memo))),
+            amount: Nat::from(amount),
+        })
+        .await
+    {
+        Ok(Ok(block_index))
+        | Ok(Err(TransferError::Duplicate {
+            duplicate_of: block_index,
+        })) => block_index,
+        // The ledger rejected the transfer outright, so it never went through
+        // and the next attempt can use a new `created_at_time`.
+        Ok(Err(TransferError::TooOld)) => {
+            update(key, |deposit| deposit.pending_credit = None);
+            return Err(format!("Failed to credit deposit {tx_digest}: too old"));
+        }
+        Ok(Err(err)) => return Err(format!("Failed to credit deposit {tx_digest}: {err}")),
+        Err(err) => {
+            return Err(format!(
+                "Failed to send a message to the ledger ({ledger_canister_id}): {err:?}"
+            ))
+        }
+    };
+
+    CREDITED_DEPOSITS.with(|credited| {
+        credited.borrow_mut().insert(
+            credited_key(tx_digest, &deposit.address),
+            KeyValue(block_index.to_string()),
+        )
+    });
+    let block_index = crate::nat_to_u64(&block_index).ok_or(format!(
+        "Credited deposit {tx_digest} in block {block_index}, which overflows and isn't recorded"
+    ))?;
+    history::record_mint(MintRecord {
+        block_index,
+        amount,
+        from: deposit.address.clone(),
+        to,
+        created_at: clock::now(),
+        origin: Some(SuiOrigin {
+            tx_digest: tx_digest.to_string(),
+            event_seq: None,
+            sender: None,
+            timestamp_ms: tx
+                .timestamp_ms
+                .as_ref()
+                .and_then(|timestamp| timestamp.parse::<u64>().ok()),
+        }),
+    });
+    log!(
+        INFO,
+        "Credited deposit {tx_digest} of {amount} to {to} in block {block_index}"
+    );
+    Ok(())
+}
@@ -0,0 +1,695 @@
+use crate::common::KeyConfig;
+use crate::constants::{
+    DEPOSIT_EVENT_TYPE_KEY, IS_LOCAL_KEY, KEY_CONFIG_KEY, LEDGER_CANISTER_ID_KEY,
+    LOCAL_MGMT_PRINCIPAL_ID_KEY, MAX_EVENTS_PAGE_SIZE, MINTER_ADDRESS_KEY, MINTER_SUI_ADDRESS_KEY,
+    SUBNET_SIZE_KEY,
+};
+use crate::helper::{
+    ControlledAddress, MintRecord, PendingWithdrawal, SweepRecord, WithdrawRecord,
+};
+use crate::models::{InitArgs, UpgradeArgs};
+use crate::{
+    clock, CONTROLLED_ADDRESSES, DEPOSIT_ADDRESSES, EVENT_LOG, MINT_RECORDS, PENDING_WITHDRAWALS,
+    PROVIDERS, SWEEPS, WITHDRAWALS, WITHDRAW_RECORDS,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{storable::Bound, Storable};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// An input that changed the bridge state.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum EventType {
+    Init(InitArgs),
+    Upgrade(UpgradeArgs),
+    /// A deposit event found on chain, about to be minted.
+    ReceivedEvent {
+        tx_digest: String,
+        event_seq: u64,
+        sender: String,
+        amount: u64,
+        to: Account,
+    },
+    /// A transfer to a deposit address, about to be credited.
+    ReceivedDeposit {
+        tx_digest: String,
+        address: String,
+        amount: u64,
+        to: Account,
+    },
+    Minted(MintRecord),
+    /// ckSUI burned for a withdrawal, queued to be paid out.
+    AcceptedWithdrawal(PendingWithdrawal),
+    SignedTransaction {
+        key: String,
+        task: String,
+        block_indices: Vec<u64>,
+    },
+    SubmittedTransaction {
+        key: String,
+        tx_digest: String,
+    },
+    FailedWithdrawalExecution {
+        block_indices: Vec<u64>,
+        tx_digest: String,
+        error: String,
+    },
+    FinalizedWithdrawal(WithdrawRecord),
+    /// The ckSUI of a failed withdrawal given back. The refund block is
+    /// unknown for withdrawals reimbursed before the log existed.
+    ReimbursedWithdrawal {
+        block_index: u64,
+        refund_block_index: Option<u64>,
+    },
+    /// The key of a minter that had none, at install or on the first upgrade.
+    ConfiguredKey(KeyConfig),
+    /// A key migration moved the minter to `key` and its address.
+    SwitchedKey {
+        migration_id: u64,
+        key: KeyConfig,
+        minter_sui_address: String,
+    },
+    /// A provider added or changed. Its headers may hold API keys and are
+    /// not logged.
+    SetProvider {
+        name: String,
+        url: String,
+    },
+    RemovedProvider {
+        name: String,
+    },
+    RegisteredDerivationPath(ControlledAddress),
+    UnregisteredDerivationPath {
+        address: String,
+    },
+    CreatedDepositAddress {
+        account: Account,
+        address: String,
+        key: KeyConfig,
+    },
+    /// A sweep started, or its outcome.
+    Swept(SweepRecord),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub timestamp: u64,
+    pub payload: EventType,
+}
+
+impl Storable for Event {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetEventsArg {
+    pub start: u64,
+    pub length: u64,
+}
+
+#[derive(CandidType)]
+pub struct GetEventsResult {
+    pub events: Vec<Event>,
+    pub total_event_count: u64,
+}
+
+/// Appends `payload` to the event log.
+pub fn record(payload: EventType) {
+    let event = Event {
+        timestamp: clock::now(),
+        payload,
+    };
+    EVENT_LOG.with(|log| {
+        log.borrow()
+            .append(&event)
+            .expect("failed to append an event to the log")
+    });
+}
+
+pub fn get(arg: GetEventsArg) -> GetEventsResult {
+    EVENT_LOG.with(|log| {
+        let log = log.borrow();
+        let length = arg.length.min(MAX_EVENTS_PAGE_SIZE);
+        GetEventsResult {
+            events: (arg.start..arg.start.saturating_add(length))
+                .map_while(|index| log.get(index))
+                .collect(),
+            total_event_count: log.len(),
+        }
+    })
+}
+
+/// Describes the configuration, history and withdrawals that predate the log
+/// as events, so that replaying it from the start gives the current state.
+pub fn seed_if_empty() {
+    if EVENT_LOG.with(|log| log.borrow().len() > 0) {
+        return;
+    }
+
+    // The configuration as it stands, as if it was set by an upgrade.
+    let config = |key: &str| crate::get(key.to_string());
+    record(EventType::Upgrade(UpgradeArgs {
+        ledger_canister_id: config(LEDGER_CANISTER_ID_KEY),
+        local_mgmt_principal_id: config(LOCAL_MGMT_PRINCIPAL_ID_KEY),
+        api_url: None,
+        is_local: config(IS_LOCAL_KEY),
+        minter_address_id: config(MINTER_ADDRESS_KEY),
+        deposit_event_type: config(DEPOSIT_EVENT_TYPE_KEY),
+        minter_sui_address: config(MINTER_SUI_ADDRESS_KEY),
+        subnet_size: config(SUBNET_SIZE_KEY).and_then(|size| size.parse().ok()),
+    }));
+    if let Some(key) = config(KEY_CONFIG_KEY).and_then(|key| serde_json::from_str(&key).ok()) {
+        record(EventType::ConfiguredKey(key));
+    }
+    let providers: Vec<(String, String)> = PROVIDERS.with(|providers| {
+        providers
+            .borrow()
+            .iter()
+            .map(|(_, provider)| (provider.name, provider.url))
+            .collect()
+    });
+    for (name, url) in providers {
+        record(EventType::SetProvider { name, url });
+    }
+    let controlled: Vec<ControlledAddress> =
+        CONTROLLED_ADDRESSES.with(|addresses| addresses.borrow().iter().map(|(_, a)| a).collect());
+    for address in controlled {
+        record(EventType::RegisteredDerivationPath(address));
+    }
+    let deposits: Vec<EventType> = DEPOSIT_ADDRESSES.with(|deposits| {
+        deposits
+            .borrow()
+            .iter()
+            .map(|(_, deposit)| EventType::CreatedDepositAddress {
+                account: Account {
+                    owner: deposit.owner,
+                    subaccount: deposit
+                        .subaccount
+                        .as_ref()
+                        .and_then(|s| s.as_slice().try_into().ok()),
+                },
+                address: deposit.address,
+                key: deposit.key,
+            })
+            .collect()
+    });
+    for deposit in deposits {
+        record(deposit);
+    }
+    let sweeps: Vec<SweepRecord> =
+        SWEEPS.with(|sweeps| sweeps.borrow().iter().map(|(_, s)| s).collect());
+    for sweep in sweeps {
+        record(EventType::Swept(sweep));
+    }
+
+    let minted: Vec<MintRecord> =
+        MINT_RECORDS.with(|records| records.borrow().iter().map(|(_, r)| r).collect());
+    for mint in minted {
+        record(EventType::Minted(mint));
+    }
+
+    let finalized: BTreeMap<u64, WithdrawRecord> =
+        WITHDRAW_RECORDS.with(|records| records.borrow().iter().collect());
+    let pending: BTreeSet<u64> =
+        PENDING_WITHDRAWALS.with(|pending| pending.borrow().iter().map(|(k, _)| k).collect());
+    let withdrawals = WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .iter()
+            .map(|(_, w)| w)
+            .collect::<Vec<_>>()
+    });
+    for withdrawal in withdrawals {
+        record(EventType::AcceptedWithdrawal(PendingWithdrawal {
+            block_index: withdrawal.block_index,
+            from: withdrawal.from,
+            recipient: withdrawal.recipient,
+            amount: withdrawal.amount,
+            created_at: withdrawal.created_at,
+        }));
+        if !pending.contains(&withdrawal.block_index)
+            && !finalized.contains_key(&withdrawal.block_index)
+        {
+            record(EventType::ReimbursedWithdrawal {
+                block_index: withdrawal.block_index,
+                refund_block_index: None,
+            });
+        }
+    }
+    for (_, withdraw) in finalized {
+        record(EventType::FinalizedWithdrawal(withdraw));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{EcdsaKeyIds, SignatureScheme};
+    use crate::helper::{KeyMigration, KeyName, MigrationState};
+    use crate::models::{
+        ExecuteTxBlockResponseResult, PublicKeyResponse, TxEffects, TxExecutionStatus,
+    };
+    use crate::providers::ProviderArgs;
+    use crate::{consolidation, deposits, history, migration, providers, withdrawals};
+    use candid::{Nat, Principal};
+    use std::fmt::Debug;
+
+    /// The state the log describes, rebuilt from the events alone.
+    #[derive(Default)]
+    struct ReplayedState {
+        config: BTreeMap<String, String>,
+        key: Option<KeyConfig>,
+        providers: BTreeMap<String, String>,
+        controlled: BTreeMap<String, ControlledAddress>,
+        deposit_addresses: BTreeMap<String, String>,
+        sweeps: BTreeMap<u64, SweepRecord>,
+        minted: BTreeMap<u64, MintRecord>,
+        accepted: BTreeSet<u64>,
+        finalized: BTreeMap<u64, WithdrawRecord>,
+        reimbursed: BTreeSet<u64>,
+    }
+
+    impl ReplayedState {
+        fn configure(&mut self, args: &UpgradeArgs) {
+            for (key, value) in crate::config_entries(args) {
+                self.config.insert(key.to_string(), value);
+            }
+        }
+
+        fn apply(&mut self, index: u64, payload: EventType) -> Result<(), String> {
+            match payload {
+                EventType::Init(args) => self.configure(&UpgradeArgs::from(args)),
+                EventType::Upgrade(args) => self.configure(&args),
+                EventType::ConfiguredKey(key) => self.key = Some(key),
+                EventType::SwitchedKey {
+                    key,
+                    minter_sui_address,
+                    ..
+                } => {
+                    self.key = Some(key);
+                    self.config
+                        .insert(MINTER_SUI_ADDRESS_KEY.to_string(), minter_sui_address);
+                }
+                EventType::SetProvider { name, url } => {
+                    self.providers.insert(name, url);
+                }
+                EventType::RemovedProvider { name } => {
+                    if self.providers.remove(&name).is_none() {
+                        return Err(format!("event {index} removes an unknown provider"));
+                    }
+                }
+                EventType::RegisteredDerivationPath(address) => {
+                    self.controlled.insert(address.address.clone(), address);
+                }
+                EventType::UnregisteredDerivationPath { address } => {
+                    if self.controlled.remove(&address).is_none() {
+                        return Err(format!("event {index} unregisters an unknown address"));
+                    }
+                }
+                EventType::CreatedDepositAddress {
+                    account, address, ..
+                } => {
+                    self.deposit_addresses.insert(account.to_string(), address);
+                }
+                EventType::Swept(sweep) => {
+                    self.sweeps.insert(sweep.id, sweep);
+                }
+                EventType::Minted(record) => {
+                    if self.minted.insert(record.block_index, record).is_some() {
+                        return Err(format!("event {index} mints a block index twice"));
+                    }
+                }
+                EventType::AcceptedWithdrawal(withdrawal) => {
+                    if !self.accepted.insert(withdrawal.block_index) {
+                        return Err(format!("event {index} accepts a withdrawal twice"));
+                    }
+                }
+                EventType::FinalizedWithdrawal(record) => {
+                    if self.reimbursed.contains(&record.block_index) {
+                        return Err(format!("event {index} finalizes a reimbursed withdrawal"));
+                    }
+                    if self.finalized.insert(record.block_index, record).is_some() {
+                        return Err(format!("event {index} finalizes a withdrawal twice"));
+                    }
+                }
+                EventType::ReimbursedWithdrawal { block_index, .. } => {
+                    if !self.accepted.contains(&block_index) {
+                        return Err(format!("event {index} reimburses an unknown withdrawal"));
+                    }
+                    if self.finalized.contains_key(&block_index) {
+                        return Err(format!("event {index} reimburses a finalized withdrawal"));
+                    }
+                    self.reimbursed.insert(block_index);
+                }
+                EventType::ReceivedEvent { .. }
+                | EventType::ReceivedDeposit { .. }
+                | EventType::SignedTransaction { .. }
+                | EventType::SubmittedTransaction { .. }
+                | EventType::FailedWithdrawalExecution { .. } => {}
+            }
+            Ok(())
+        }
+
+        /// Accepted withdrawals that were neither paid out nor reimbursed.
+        fn open_withdrawals(&self) -> BTreeSet<u64> {
+            self.accepted
+                .iter()
+                .filter(|block_index| {
+                    !self.finalized.contains_key(block_index)
+                        && !self.reimbursed.contains(block_index)
+                })
+                .copied()
+                .collect()
+        }
+    }
+
+    /// Rebuilds the state from the events `get` returns, page by page.
+    fn replay() -> Result<ReplayedState, String> {
+        let mut state = ReplayedState::default();
+        let mut index = 0;
+        loop {
+            let page = get(GetEventsArg {
+                start: index,
+                length: MAX_EVENTS_PAGE_SIZE,
+            });
+            if page.events.is_empty() {
+                return Ok(state);
+            }
+            for event in page.events {
+                state.apply(index, event.payload)?;
+                index += 1;
+            }
+        }
+    }
+
+    fn compare<T: PartialEq + Debug>(what: &str, replayed: T, live: T) -> Result<(), String> {
+        if replayed != live {
+            return Err(format!(
+                "replayed {what} {replayed:?}, the canister has {live:?}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replays the log and checks that it gives the state in the maps.
+    fn check_replay() -> Result<(), String> {
+        let state = replay()?;
+
+        let config: BTreeMap<String, String> = [
+            LEDGER_CANISTER_ID_KEY,
+            LOCAL_MGMT_PRINCIPAL_ID_KEY,
+            IS_LOCAL_KEY,
+            MINTER_ADDRESS_KEY,
+            DEPOSIT_EVENT_TYPE_KEY,
+            MINTER_SUI_ADDRESS_KEY,
+            SUBNET_SIZE_KEY,
+        ]
+        .into_iter()
+        .filter_map(|key| Some((key.to_string(), crate::get(key.to_string())?)))
+        .collect();
+        compare("config", &state.config, &config)?;
+        let key: Option<KeyConfig> = crate::get(KEY_CONFIG_KEY.to_string())
+            .map(|key| serde_json::from_str(&key).expect("invalid key config"));
+        compare("key", state.key, key)?;
+
+        let providers: BTreeMap<String, String> = PROVIDERS.with(|providers| {
+            providers
+                .borrow()
+                .iter()
+                .map(|(_, provider)| (provider.name, provider.url))
+                .collect()
+        });
+        compare("providers", &state.providers, &providers)?;
+        let controlled: BTreeMap<String, ControlledAddress> =
+            CONTROLLED_ADDRESSES.with(|addresses| {
+                addresses
+                    .borrow()
+                    .iter()
+                    .map(|(key, address)| (key.0, address))
+                    .collect()
+            });
+        compare("controlled addresses", &state.controlled, &controlled)?;
+        let deposit_addresses: BTreeMap<String, String> = DEPOSIT_ADDRESSES.with(|deposits| {
+            deposits
+                .borrow()
+                .iter()
+                .map(|(key, deposit)| (key.0, deposit.address))
+                .collect()
+        });
+        compare(
+            "deposit addresses",
+            &state.deposit_addresses,
+            &deposit_addresses,
+        )?;
+        let sweeps: BTreeMap<u64, SweepRecord> =
+            SWEEPS.with(|sweeps| sweeps.borrow().iter().collect());
+        compare("sweeps", &state.sweeps, &sweeps)?;
+
+        let minted: BTreeMap<u64, MintRecord> =
+            MINT_RECORDS.with(|records| records.borrow().iter().collect());
+        compare("mint records", &state.minted, &minted)?;
+        let finalized: BTreeMap<u64, WithdrawRecord> =
+            WITHDRAW_RECORDS.with(|records| records.borrow().iter().collect());
+        compare("withdraw records", &state.finalized, &finalized)?;
+        let accepted: BTreeSet<u64> =
+            WITHDRAWALS.with(|withdrawals| withdrawals.borrow().iter().map(|(k, _)| k).collect());
+        compare("withdrawals", &state.accepted, &accepted)?;
+        let pending: BTreeSet<u64> =
+            PENDING_WITHDRAWALS.with(|pending| pending.borrow().iter().map(|(k, _)| k).collect());
+        compare("open withdrawals", &state.open_withdrawals(), &pending)
+    }
+
+    const SECOND: u64 = 1_000_000_000;
+
+    const KEY: KeyConfig = KeyConfig {
+        key_id: EcdsaKeyIds::TestKey1,
+        scheme: SignatureScheme::Ed25519,
+    };
+
+    fn tick() {
+        clock::set(clock::now() + SECOND);
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn sui_address(id: u8) -> String {
+        format!("0x{}", hex::encode([id; 32]))
+    }
+
+    fn init_args() -> InitArgs {
+        InitArgs {
+            ledger_canister_id: principal(10).to_text(),
+            local_mgmt_principal_id: principal(11).to_text(),
+            api_url: "https://rpc.example".to_string(),
+            is_local: "false".to_string(),
+            minter_address_id: principal(12).to_text(),
+            deposit_event_type: Some(format!("{}::ckSuiHelper::ReceivedSui", sui_address(9))),
+            minter_sui_address: sui_address(1),
+            key_config: Some(KEY),
+            subnet_size: None,
+        }
+    }
+
+    fn provider(name: &str, url: &str) -> ProviderArgs {
+        ProviderArgs {
+            name: name.to_string(),
+            url: url.to_string(),
+            headers: None,
+        }
+    }
+
+    fn controlled(id: u8, label: &str) -> ControlledAddress {
+        ControlledAddress {
+            address: sui_address(id),
+            key: KEY,
+            derivation_path: vec![label.as_bytes().to_vec()],
+            label: label.to_string(),
+            registered_at: clock::now(),
+        }
+    }
+
+    fn withdrawal(block_index: u64) -> PendingWithdrawal {
+        PendingWithdrawal {
+            block_index,
+            from: principal(2),
+            recipient: sui_address(3),
+            amount: 1_000,
+            created_at: clock::now(),
+        }
+    }
+
+    fn executed(digest: &str) -> ExecuteTxBlockResponseResult {
+        ExecuteTxBlockResponseResult {
+            digest: digest.to_string(),
+            effects: Some(TxEffects {
+                status: TxExecutionStatus {
+                    status: "success".to_string(),
+                    error: None,
+                },
+            }),
+            checkpoint: None,
+        }
+    }
+
+    /// Runs a short bridge history through the functions the endpoints and
+    /// tasks use, one second apart.
+    fn run_bridge() {
+        tick();
+        crate::install(init_args()).unwrap();
+
+        tick();
+        providers::set(provider("backup", "https://backup.example")).unwrap();
+        tick();
+        providers::set(provider("backup", "https://other.example")).unwrap();
+        tick();
+        providers::remove("backup".to_string()).unwrap();
+
+        tick();
+        consolidation::add(controlled(4, "refunds"));
+        consolidation::add(controlled(8, "old"));
+        tick();
+        consolidation::unregister(sui_address(8)).unwrap();
+
+        tick();
+        let account = Account::from(principal(2));
+        deposits::add_address(
+            account,
+            KEY,
+            deposits::derivation_path(&account),
+            sui_address(6),
+        );
+        tick();
+        history::record_mint(MintRecord {
+            block_index: 1,
+            amount: 2_000,
+            from: sui_address(6),
+            to: account,
+            created_at: clock::now(),
+            origin: None,
+        });
+
+        // Withdrawal 2 is paid out, 3 is still pending and 4 is reimbursed.
+        tick();
+        for block_index in [2, 3, 4] {
+            withdrawals::enqueue(withdrawal(block_index));
+        }
+        tick();
+        withdrawals::finalize(&[2], "withdrawal");
+        tick();
+        withdrawals::record_reimbursed(&withdrawal(4), Nat::from(5u64));
+
+        tick();
+        consolidation::start_sweep(
+            &sui_address(4),
+            &sui_address(1),
+            500_000_000,
+            vec![sui_address(5)],
+            "sweep",
+        );
+        tick();
+        consolidation::record_outcome("sweep", Ok(executed("sweep")));
+
+        tick();
+        let new_key = KeyConfig {
+            scheme: SignatureScheme::Secp256k1,
+            ..KEY
+        };
+        migration::switch(
+            &KeyMigration {
+                id: 0,
+                old_key: KEY,
+                new_key,
+                old_address: sui_address(1),
+                new_address: sui_address(7),
+                tx_digests: vec![],
+                state: MigrationState::InProgress,
+                error: None,
+                started_at: clock::now(),
+                completed_at: None,
+            },
+            &PublicKeyResponse {
+                public_key: vec![],
+                public_key_bs64: String::new(),
+            },
+        );
+    }
+
+    #[test]
+    fn replaying_the_log_gives_the_state() {
+        run_bridge();
+        assert_eq!(check_replay(), Ok(()));
+    }
+
+    #[test]
+    fn events_are_stamped_with_the_clock() {
+        run_bridge();
+        let events = get(GetEventsArg {
+            start: 0,
+            length: MAX_EVENTS_PAGE_SIZE,
+        })
+        .events;
+        assert!(matches!(events[0].payload, EventType::Init(_)));
+        assert_eq!(events[0].timestamp, SECOND);
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        assert_eq!(events.last().unwrap().timestamp, clock::now());
+    }
+
+    #[test]
+    fn replay_detects_state_the_log_does_not_explain() {
+        run_bridge();
+        PROVIDERS.with(|providers| {
+            let mut providers = providers.borrow_mut();
+            let mut provider = providers.get(&KeyName("default".to_string())).unwrap();
+            provider.url = "https://other.example".to_string();
+            providers.insert(KeyName("default".to_string()), provider);
+        });
+        assert!(check_replay().unwrap_err().contains("providers"));
+    }
+
+    #[test]
+    fn replay_detects_inputs_missing_from_the_state() {
+        run_bridge();
+        record(EventType::ReimbursedWithdrawal {
+            block_index: 3,
+            refund_block_index: Some(6),
+        });
+        assert!(check_replay().unwrap_err().contains("open withdrawals"));
+
+        MINT_RECORDS.with(|records| records.borrow_mut().remove(&1));
+        assert!(check_replay().unwrap_err().contains("mint records"));
+    }
+
+    #[test]
+    fn replay_rejects_inconsistent_logs() {
+        record(EventType::Minted(MintRecord {
+            block_index: 1,
+            amount: 1,
+            from: sui_address(1),
+            to: Account::from(principal(2)),
+            created_at: 0,
+            origin: None,
+        }));
+        record(EventType::ReimbursedWithdrawal {
+            block_index: 9,
+            refund_block_index: None,
+        });
+        assert_eq!(
+            check_replay().unwrap_err(),
+            "event 1 reimburses an unknown withdrawal"
+        );
+    }
+}
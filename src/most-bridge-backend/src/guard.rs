@@ -0,0 +1,27 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+thread_local! {
+    static RUNNING_TASKS: RefCell<BTreeSet<&'static str>> = RefCell::new(BTreeSet::new());
+}
+
+/// Makes sure a background task never runs concurrently with itself.
+pub struct TaskGuard(&'static str);
+
+impl TaskGuard {
+    pub fn new(task: &'static str) -> Option<Self> {
+        RUNNING_TASKS.with(|tasks| {
+            if tasks.borrow_mut().insert(task) {
+                Some(TaskGuard(task))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        RUNNING_TASKS.with(|tasks| tasks.borrow_mut().remove(self.0));
+    }
+}
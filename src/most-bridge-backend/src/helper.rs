@@ -1,8 +1,12 @@
-use candid::CandidType;
+use crate::common::KeyConfig;
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::management_canister::http_request::HttpHeader;
 use ic_stable_structures::{
     memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, Storable,
 };
+use icrc_ledger_types::icrc1::account::Account;
 use serde::Deserialize;
+use std::borrow::Cow;
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -35,3 +39,442 @@ impl Storable for KeyValue {
 
     const BOUND: Bound = Bound::Unbounded;
 }
+
+/// A withdrawal whose ckSUI has already been taken from the user and which is
+/// waiting to be paid out in the next Sui transaction batch.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingWithdrawal {
+    pub block_index: u64,
+    pub from: Principal,
+    pub recipient: String,
+    pub amount: u64,
+    pub created_at: u64,
+}
+
+impl Storable for PendingWithdrawal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A SUI coin object owned by the minter's Sui address.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OwnedCoin {
+    pub object_id: String,
+    pub version: u64,
+    pub digest: String,
+    pub balance: u64,
+}
+
+impl Storable for OwnedCoin {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ObjectVersion {
+    pub object_id: String,
+    pub version: u64,
+}
+
+/// A transaction built for the minter's Sui address. Once signed, the exact
+/// same bytes and signature are re-submitted until the outcome is known.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SignedTransaction {
+    pub task: String,
+    pub tx_bytes: String,
+    pub signature: Option<String>,
+    pub objects: Vec<ObjectVersion>,
+    pub block_indices: Vec<u64>,
+    pub created_at: u64,
+}
+
+impl Storable for SignedTransaction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A Sui address derived for an ICRC-1 account. Plain SUI transfers to it are
+/// credited to the account.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DepositAddress {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+    pub address: String,
+    pub key: KeyConfig,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub cursor: Option<String>,
+    pub created_at: u64,
+    /// Last time the address was handed out by `get_deposit_address`.
+    pub requested_at: Option<u64>,
+    /// Last time a deposit to the address was credited.
+    pub credited_at: Option<u64>,
+    /// Last time the address held nothing worth sweeping.
+    pub swept_at: Option<u64>,
+    /// Digest of the deposit being credited and the `created_at_time` of its
+    /// ledger transfer, fixed before the first attempt.
+    pub pending_credit: Option<(String, u64)>,
+}
+
+impl DepositAddress {
+    /// Whether the address was requested or credited within `period` of `now`,
+    /// and is therefore scanned for deposits.
+    pub fn is_active(&self, now: u64, period: u64) -> bool {
+        let last = self
+            .requested_at
+            .unwrap_or(self.created_at)
+            .max(self.credited_at.unwrap_or_default());
+        now.saturating_sub(last) <= period
+    }
+
+    /// Whether a deposit was credited since the address was last found empty.
+    /// Addresses never checked are assumed to hold a balance.
+    pub fn holds_balance(&self) -> bool {
+        match self.swept_at {
+            Some(swept_at) => self.credited_at.map_or(false, |c| c > swept_at),
+            None => true,
+        }
+    }
+}
+
+impl Storable for DepositAddress {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// An address the minter key controls under a derivation path registered by
+/// a controller.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ControlledAddress {
+    pub address: String,
+    pub key: KeyConfig,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub label: String,
+    pub registered_at: u64,
+}
+
+impl Storable for ControlledAddress {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SweepStatus {
+    Submitted,
+    Succeeded,
+    Failed,
+}
+
+/// A transfer of all SUI held by a minter-controlled address into the main address.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SweepRecord {
+    pub id: u64,
+    pub from: String,
+    pub to: String,
+    pub balance: u64,
+    pub coins: Vec<String>,
+    pub tx_digest: String,
+    pub status: SweepStatus,
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for SweepRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A Sui JSON-RPC full node the minter can send requests to. `headers` are
+/// sent with every request and may hold API keys.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Provider {
+    pub name: String,
+    pub url: String,
+    pub headers: Vec<HttpHeader>,
+    pub health: u32,
+    pub successes: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+    pub last_failure_at: Option<u64>,
+    pub added_at: u64,
+}
+
+impl Storable for Provider {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// HTTP outcalls made to one endpoint and the cycles they were charged.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct OutcallCost {
+    pub calls: u64,
+    pub cycles_charged: u128,
+}
+
+impl Storable for OutcallCost {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrationState {
+    InProgress,
+    Completed,
+    Failed,
+}
+
+/// A move of the minter from one threshold key, and its Sui address, to another.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KeyMigration {
+    pub id: u64,
+    pub old_key: KeyConfig,
+    pub new_key: KeyConfig,
+    pub old_address: String,
+    pub new_address: String,
+    pub tx_digests: Vec<String>,
+    pub state: MigrationState,
+    pub error: Option<String>,
+    pub started_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for KeyMigration {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WithdrawalState {
+    Pending,
+    Submitted,
+    Finalized,
+    Failed,
+    /// Failed and given back in ckSUI. The refund block is unknown if it
+    /// overflows a u64.
+    Reimbursed {
+        refund_block_index: Option<u64>,
+    },
+}
+
+/// Lifecycle of a withdrawal, kept after it has been paid out.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Withdrawal {
+    pub block_index: u64,
+    pub from: Principal,
+    pub recipient: String,
+    pub amount: u64,
+    pub created_at: u64,
+    pub state: WithdrawalState,
+    pub tx_digest: Option<String>,
+    pub error: Option<String>,
+    pub attempts: u32,
+    pub updated_at: u64,
+    /// `created_at_time` of the refund transfer, fixed on the first attempt so
+    /// that the ledger deduplicates retries.
+    pub refund_created_at: Option<u64>,
+    /// Set when a refund attempt with `refund_created_at` had an unknown
+    /// outcome, until the ledger gives a definite answer for it.
+    pub refund_unclear_since: Option<u64>,
+}
+
+impl Storable for Withdrawal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Upper bounds on the encoded size of the history records. Their fields are
+// numbers, principals, accounts and Sui addresses or digests, which all have
+// a fixed maximum length, so these leave plenty of headroom.
+const MINT_RECORD_MAX_SIZE: u32 = 1024;
+const WITHDRAW_RECORD_MAX_SIZE: u32 = 1024;
+
+/// Where on Sui the SUI behind a mint came from.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SuiOrigin {
+    pub tx_digest: String,
+    /// Sequence number of the bridge event within the transaction, for mints
+    /// of bridge events. Deposits are whole transactions and have none.
+    pub event_seq: Option<u64>,
+    pub sender: Option<String>,
+    pub timestamp_ms: Option<u64>,
+}
+
+/// ckSUI minted on the ledger for SUI received on Sui. `from` is the Sui
+/// address the SUI came from: the sender of a bridge event, or the deposit
+/// address of a deposit.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MintRecord {
+    pub block_index: u64,
+    pub amount: u64,
+    pub from: String,
+    pub to: Account,
+    pub created_at: u64,
+    /// None for records migrated from before it was kept.
+    pub origin: Option<SuiOrigin>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct MintRecordV1 {
+    block_index: u64,
+    amount: u64,
+    from: String,
+    to: Account,
+    created_at: u64,
+}
+
+// Stored form of MintRecord. Adding a variant lets the record change shape
+// without losing the ones already in stable memory.
+#[derive(CandidType, Deserialize)]
+enum VersionedMintRecord {
+    V1(MintRecordV1),
+    V2(MintRecord),
+}
+
+impl MintRecord {
+    /// Whether the record fits into MINT_RECORD_MAX_SIZE bytes, which storing
+    /// it asserts.
+    pub fn fits(&self) -> bool {
+        Encode!(&VersionedMintRecord::V2(self.clone()))
+            .map_or(false, |bytes| bytes.len() <= MINT_RECORD_MAX_SIZE as usize)
+    }
+}
+
+impl Storable for MintRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let bytes = Encode!(&VersionedMintRecord::V2(self.clone())).unwrap();
+        assert!(
+            bytes.len() <= MINT_RECORD_MAX_SIZE as usize,
+            "Mint record {} is too large",
+            self.block_index
+        );
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match Decode!(bytes.as_ref(), VersionedMintRecord).unwrap() {
+            VersionedMintRecord::V1(record) => MintRecord {
+                block_index: record.block_index,
+                amount: record.amount,
+                from: record.from,
+                to: record.to,
+                created_at: record.created_at,
+                origin: None,
+            },
+            VersionedMintRecord::V2(record) => record,
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MINT_RECORD_MAX_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+/// A withdrawal paid out on Sui, kept once its transaction is final.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WithdrawRecord {
+    pub block_index: u64,
+    pub amount: u64,
+    pub from: Principal,
+    pub recipient: Option<String>,
+    pub tx_digest: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+enum VersionedWithdrawRecord {
+    V1(WithdrawRecord),
+}
+
+impl Storable for WithdrawRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let bytes = Encode!(&VersionedWithdrawRecord::V1(self.clone())).unwrap();
+        assert!(
+            bytes.len() <= WITHDRAW_RECORD_MAX_SIZE as usize,
+            "Withdraw record {} is too large",
+            self.block_index
+        );
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match Decode!(bytes.as_ref(), VersionedWithdrawRecord).unwrap() {
+            VersionedWithdrawRecord::V1(record) => record,
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: WITHDRAW_RECORD_MAX_SIZE,
+        is_fixed_size: false,
+    };
+}
@@ -0,0 +1,426 @@
+use crate::certification;
+use crate::constants::{DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT, MAX_HISTORY_SCAN};
+use crate::events::{self, EventType};
+use crate::helper::{KeyName, KeyValue, Memory, MintRecord, WithdrawRecord};
+use crate::logs::{Sort, INFO};
+use crate::{
+    sui, ADDRESS_INDEX, LEGACY_FINALIZED_TRANSACTIONS, LEGACY_MINTED_TRANSACTIONS, MINT_RECORDS,
+    PRINCIPAL_INDEX, WITHDRAWALS, WITHDRAW_RECORDS,
+};
+use candid::{CandidType, Principal};
+use ic_canister_log::log;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Selects a page of mint or withdraw history. Records are ordered by ledger
+/// block index; `start` is the block index to start from, inclusive, and is
+/// what `next` of the previous page holds. Times are nanoseconds since the
+/// epoch, and both bounds are inclusive.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct HistoryQuery {
+    pub start: Option<u64>,
+    pub limit: Option<u64>,
+    pub from_time: Option<u64>,
+    pub to_time: Option<u64>,
+    pub principal: Option<Principal>,
+    pub sort: Option<Sort>,
+}
+
+#[derive(CandidType, Debug)]
+pub struct HistoryPage<T> {
+    pub records: Vec<T>,
+    /// Number of records across all pages. Only set when nothing is filtered,
+    /// as counting the matches would mean reading every record.
+    pub total: Option<u64>,
+    /// `start` of the next page, if there may be one.
+    pub next: Option<u64>,
+}
+
+/// The bridge activity of one principal or Sui address.
+#[derive(CandidType, Debug)]
+pub struct Activity {
+    pub mints: HistoryPage<MintRecord>,
+    pub withdrawals: HistoryPage<WithdrawRecord>,
+}
+
+// Kinds of records in the indexes. Mints are indexed by the principal they
+// credit, and by Sui address when they come from a deposit address.
+const MINT: &str = "mint";
+const DEPOSIT: &str = "deposit";
+const WITHDRAW: &str = "withdraw";
+
+fn index_key(owner: &str, kind: &str, block_index: u64) -> KeyName {
+    // Zero padded so that the keys of one owner and kind sort by block index.
+    KeyName(format!("{owner}:{kind}:{block_index:020}"))
+}
+
+pub fn is_descending(query: &HistoryQuery) -> bool {
+    matches!(query.sort, Some(Sort::Descending))
+}
+
+/// The entries of `map` at or below `start`, largest key first. Each step is
+/// one lookup, so only the entries taken are read.
+pub fn descending<'a, K: Storable + Ord + Clone + 'a, V: Storable + 'a>(
+    map: &'a StableBTreeMap<K, V, Memory>,
+    start: Option<K>,
+) -> impl Iterator<Item = (K, V)> + 'a {
+    let below = move |key: &K| map.iter_upper_bound(key).next().filter(|(k, _)| k < key);
+    let mut next = match start {
+        Some(start) => map
+            .get(&start)
+            .map(|value| (start.clone(), value))
+            .or_else(|| below(&start)),
+        None => map.last_key_value(),
+    };
+    std::iter::from_fn(move || {
+        let entry = next.take()?;
+        next = below(&entry.0);
+        Some(entry)
+    })
+}
+
+/// The records of `records` from `query.start` on, in the order of the query.
+fn ordered<'a, T: Storable + 'a>(
+    records: &'a StableBTreeMap<u64, T, Memory>,
+    query: &HistoryQuery,
+) -> Box<dyn Iterator<Item = (u64, T)> + 'a> {
+    if is_descending(query) {
+        Box::new(descending(records, query.start))
+    } else {
+        Box::new(records.range(query.start.unwrap_or(0)..))
+    }
+}
+
+/// Block indices of the `kind` records of `owner` in `index`, from
+/// `query.start` on, in the order of the query.
+fn indexed<'a>(
+    index: &'a StableBTreeMap<KeyName, (), Memory>,
+    owner: &str,
+    kind: &str,
+    query: &HistoryQuery,
+) -> impl Iterator<Item = u64> + 'a {
+    let prefix = format!("{owner}:{kind}:");
+    let keys: Box<dyn Iterator<Item = (KeyName, ())> + 'a> = if is_descending(query) {
+        let start = index_key(owner, kind, query.start.unwrap_or(u64::MAX));
+        Box::new(descending(index, Some(start)))
+    } else {
+        Box::new(index.range(index_key(owner, kind, query.start.unwrap_or(0))..))
+    };
+    keys.take_while(move |(key, _)| key.0.starts_with(&prefix))
+        .filter_map(|(key, _)| key.0.rsplit(':').next()?.parse::<u64>().ok())
+}
+
+trait HistoryRecord {
+    fn created_at(&self) -> u64;
+    /// The ICRC principal on the ledger side of the record.
+    fn principal(&self) -> Principal;
+}
+
+impl HistoryRecord for MintRecord {
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    fn principal(&self) -> Principal {
+        self.to.owner
+    }
+}
+
+impl HistoryRecord for WithdrawRecord {
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    fn principal(&self) -> Principal {
+        self.from
+    }
+}
+
+fn index_mint(record: &MintRecord) {
+    PRINCIPAL_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            index_key(&record.to.owner.to_text(), MINT, record.block_index),
+            (),
+        )
+    });
+    if let Some(address) = sui::canonical_address(&record.from) {
+        ADDRESS_INDEX.with(|index| {
+            index
+                .borrow_mut()
+                .insert(index_key(&address, DEPOSIT, record.block_index), ())
+        });
+    }
+}
+
+fn index_withdrawal(record: &WithdrawRecord) {
+    PRINCIPAL_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            index_key(&record.from.to_text(), WITHDRAW, record.block_index),
+            (),
+        )
+    });
+    if let Some(address) = record.recipient.as_deref().and_then(sui::canonical_address) {
+        ADDRESS_INDEX.with(|index| {
+            index
+                .borrow_mut()
+                .insert(index_key(&address, WITHDRAW, record.block_index), ())
+        });
+    }
+}
+
+// The record, its event, its index entries and its certified leaf are
+// written without an await in between, so they are committed together.
+pub fn record_mint(record: MintRecord) {
+    events::record(EventType::Minted(record.clone()));
+    index_mint(&record);
+    certification::certify_mint(&record);
+    MINT_RECORDS.with(|records| records.borrow_mut().insert(record.block_index, record));
+}
+
+pub fn record_withdrawal(record: WithdrawRecord) {
+    events::record(EventType::FinalizedWithdrawal(record.clone()));
+    index_withdrawal(&record);
+    certification::certify_withdraw(&record);
+    WITHDRAW_RECORDS.with(|records| records.borrow_mut().insert(record.block_index, record));
+}
+
+/// Indexes the records written before the indexes existed.
+pub fn backfill_indexes() {
+    let already_indexed = PRINCIPAL_INDEX.with(|index| !index.borrow().is_empty());
+    if already_indexed {
+        return;
+    }
+    MINT_RECORDS.with(|records| {
+        for (_, record) in records.borrow().iter() {
+            index_mint(&record);
+        }
+    });
+    WITHDRAW_RECORDS.with(|records| {
+        for (_, record) in records.borrow().iter() {
+            index_withdrawal(&record);
+        }
+    });
+}
+
+fn indexed_mints(
+    index: &StableBTreeMap<KeyName, (), Memory>,
+    owner: &str,
+    kind: &str,
+    query: &HistoryQuery,
+) -> HistoryPage<MintRecord> {
+    MINT_RECORDS.with(|records| {
+        let records = records.borrow();
+        page(
+            indexed(index, owner, kind, query)
+                .filter_map(|block_index| records.get(&block_index).map(|r| (block_index, r))),
+            query,
+            None,
+        )
+    })
+}
+
+fn indexed_withdrawals(
+    index: &StableBTreeMap<KeyName, (), Memory>,
+    owner: &str,
+    query: &HistoryQuery,
+) -> HistoryPage<WithdrawRecord> {
+    WITHDRAW_RECORDS.with(|records| {
+        let records = records.borrow();
+        page(
+            indexed(index, owner, WITHDRAW, query)
+                .filter_map(|block_index| records.get(&block_index).map(|r| (block_index, r))),
+            query,
+            None,
+        )
+    })
+}
+
+pub fn minted(query: HistoryQuery) -> HistoryPage<MintRecord> {
+    match query.principal {
+        Some(principal) => PRINCIPAL_INDEX
+            .with(|index| indexed_mints(&index.borrow(), &principal.to_text(), MINT, &query)),
+        None => MINT_RECORDS.with(|records| {
+            let records = records.borrow();
+            page(ordered(&records, &query), &query, total(&records, &query))
+        }),
+    }
+}
+
+pub fn finalized(query: HistoryQuery) -> HistoryPage<WithdrawRecord> {
+    match query.principal {
+        Some(principal) => PRINCIPAL_INDEX
+            .with(|index| indexed_withdrawals(&index.borrow(), &principal.to_text(), &query)),
+        None => WITHDRAW_RECORDS.with(|records| {
+            let records = records.borrow();
+            page(ordered(&records, &query), &query, total(&records, &query))
+        }),
+    }
+}
+
+/// Mints credited to `principal` and withdrawals it made.
+pub fn principal_activity(principal: Principal, query: HistoryQuery) -> Activity {
+    let owner = principal.to_text();
+    PRINCIPAL_INDEX.with(|index| {
+        let index = index.borrow();
+        Activity {
+            mints: indexed_mints(&index, &owner, MINT, &query),
+            withdrawals: indexed_withdrawals(&index, &owner, &query),
+        }
+    })
+}
+
+/// Deposits received at `address` and withdrawals paid out to it.
+pub fn address_activity(address: &str, query: HistoryQuery) -> Result<Activity, String> {
+    let owner =
+        sui::canonical_address(address).ok_or(format!("Invalid Sui address {}", address))?;
+    Ok(ADDRESS_INDEX.with(|index| {
+        let index = index.borrow();
+        Activity {
+            mints: indexed_mints(&index, &owner, DEPOSIT, &query),
+            withdrawals: indexed_withdrawals(&index, &owner, &query),
+        }
+    }))
+}
+
+fn is_selected<T: HistoryRecord>(record: &T, query: &HistoryQuery) -> bool {
+    query
+        .from_time
+        .map_or(true, |from| record.created_at() >= from)
+        && query.to_time.map_or(true, |to| record.created_at() <= to)
+        && query
+            .principal
+            .map_or(true, |principal| record.principal() == principal)
+}
+
+/// Number of records in `records`, if the query doesn't filter them.
+fn total<T: Storable>(
+    records: &StableBTreeMap<u64, T, Memory>,
+    query: &HistoryQuery,
+) -> Option<u64> {
+    let filtered =
+        query.from_time.is_some() || query.to_time.is_some() || query.principal.is_some();
+    (!filtered).then(|| records.len())
+}
+
+/// Keeps the first `limit` of `records` matching the filters. `records` are
+/// already in the order of the query and start at `query.start`; they are
+/// read lazily and at most MAX_HISTORY_SCAN of them, so `next` is set when
+/// the page is full or the scan is cut short.
+fn page<T: HistoryRecord>(
+    records: impl Iterator<Item = (u64, T)>,
+    query: &HistoryQuery,
+    total: Option<u64>,
+) -> HistoryPage<T> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT) as usize;
+
+    let mut selected = Vec::new();
+    let mut next = None;
+    for (scanned, (block_index, record)) in records.enumerate() {
+        if selected.len() == limit || scanned == MAX_HISTORY_SCAN {
+            next = Some(block_index);
+            break;
+        }
+        if is_selected(&record, query) {
+            selected.push(record);
+        }
+    }
+
+    HistoryPage {
+        records: selected,
+        total,
+        next,
+    }
+}
+
+// Shape of the JSON strings mint and withdraw history used to be kept as.
+// Mint records have `to`, withdraw records have `tx`.
+#[derive(Deserialize)]
+struct LegacyRecord {
+    block_index: String,
+    date: String,
+    amount: String,
+    from: String,
+    to: Option<String>,
+    tx: Option<String>,
+}
+
+fn parse_u64(value: &str, field: &str) -> Result<u64, String> {
+    value
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("Invalid {field} {value}: {e}"))
+}
+
+fn parse_legacy_mint(json: &str) -> Result<MintRecord, String> {
+    let legacy: LegacyRecord = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let to = legacy.to.ok_or("Missing to")?;
+    Ok(MintRecord {
+        block_index: parse_u64(&legacy.block_index, "block_index")?,
+        amount: parse_u64(&legacy.amount, "amount")?,
+        from: legacy.from,
+        to: Account::from_str(&to).map_err(|e| format!("Invalid to {to}: {e:?}"))?,
+        created_at: parse_u64(&legacy.date, "date")?,
+        origin: None,
+    })
+}
+
+fn parse_legacy_withdraw(json: &str) -> Result<WithdrawRecord, String> {
+    let legacy: LegacyRecord = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let block_index = parse_u64(&legacy.block_index, "block_index")?;
+    // `tx` holds the explorer URL, which ends with the digest.
+    let tx = legacy.tx.ok_or("Missing tx")?;
+    let tx_digest = tx.rsplit('/').next().unwrap_or(&tx).to_string();
+    Ok(WithdrawRecord {
+        block_index,
+        amount: parse_u64(&legacy.amount, "amount")?,
+        from: Principal::from_text(&legacy.from)
+            .map_err(|e| format!("Invalid from {}: {e}", legacy.from))?,
+        recipient: WITHDRAWALS
+            .with(|withdrawals| withdrawals.borrow().get(&block_index))
+            .map(|withdrawal| withdrawal.recipient),
+        tx_digest,
+        created_at: parse_u64(&legacy.date, "date")?,
+    })
+}
+
+/// Moves the history kept as JSON strings into typed records. Entries that
+/// can't be parsed stay in the legacy maps and are logged, so nothing is lost.
+pub fn migrate_legacy() {
+    let minted: Vec<(KeyName, KeyValue)> =
+        LEGACY_MINTED_TRANSACTIONS.with(|transactions| transactions.borrow().iter().collect());
+    let mut migrated = 0;
+    for (key, value) in minted {
+        match parse_legacy_mint(&value.0) {
+            Ok(record) => {
+                record_mint(record);
+                LEGACY_MINTED_TRANSACTIONS
+                    .with(|transactions| transactions.borrow_mut().remove(&key));
+                migrated += 1;
+            }
+            Err(e) => log!(INFO, "Keeping legacy mint record {}: {e}", key.0),
+        }
+    }
+
+    let finalized: Vec<(KeyName, KeyValue)> =
+        LEGACY_FINALIZED_TRANSACTIONS.with(|transactions| transactions.borrow().iter().collect());
+    for (key, value) in finalized {
+        match parse_legacy_withdraw(&value.0) {
+            Ok(record) => {
+                record_withdrawal(record);
+                LEGACY_FINALIZED_TRANSACTIONS
+                    .with(|transactions| transactions.borrow_mut().remove(&key));
+                migrated += 1;
+            }
+            Err(e) => log!(INFO, "Keeping legacy withdraw record {}: {e}", key.0),
+        }
+    }
+
+    if migrated > 0 {
+        log!(INFO, "Migrated {migrated} legacy history records");
+    }
+}
@@ -1,40 +1,66 @@
+use certification::Certified;
 use common::{
-    Context, ECDSAPublicKey, ECDSAPublicKeyReply, EcdsaKeyIds, SignWithECDSA, SignWithECDSAReply,
-    TxDigestResponse, WithdrawResponse,
+    ECDSAPublicKey, ECDSAPublicKeyReply, EcdsaKeyIds, KeyConfig, SchnorrPublicKey,
+    SchnorrPublicKeyReply, SignWithECDSA, SignWithECDSAReply, SignWithSchnorr,
+    SignWithSchnorrReply, SignatureScheme, WithdrawResponse, WithdrawalStatus,
 };
 use constants::{
-    API_URL_KEY, EXECUTE_TX_BLOCK_URL_KEY, IS_LOCAL_KEY, LEDGER_CANISTER_ID_KEY,
-    LOCAL_MGMT_PRINCIPAL_ID_KEY, MINTER_ADDRESS_KEY, PROCESSED_TX_DIGEST_KEY, QUERY_EVENTS_URL_KEY,
-    QUERY_SUI_EVENTS_INTERVAL, TX_DIGEST_URL_KEY,
+    COIN_MAINTENANCE_INTERVAL, CONSOLIDATION_INTERVAL, DEFAULT_PROVIDER_NAME,
+    DEPOSIT_EVENT_TYPE_KEY, EVENTS_PAGE_SIZE, FLUSH_WITHDRAWALS_INTERVAL, IS_LOCAL_KEY,
+    KEY_CONFIG_KEY, LEDGER_CANISTER_ID_KEY, LEGACY_API_URL_KEY, LOCAL_MGMT_PRINCIPAL_ID_KEY,
+    MAX_RAW_SENDER_CHARS, MAX_WITHDRAWAL_BATCH_SIZE, MINTER_ADDRESS_KEY, MINTER_SUI_ADDRESS_KEY,
+    PROCESSED_EVENT_SEQ_KEY, PROCESSED_TX_DIGEST_KEY, PUBLIC_KEY_CONFIG_KEY, PUBLIC_KEY_KEY,
+    QUERY_SUI_EVENTS_INTERVAL, SCAN_DEPOSITS_INTERVAL, SUBNET_SIZE_KEY,
 };
-use helper::{KeyName, KeyValue, Memory};
-use ic_canister_log::log;
-use ic_cdk::api::management_canister::http_request::{
-    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
-    TransformContext,
+use events::{Event, EventType, GetEventsArg, GetEventsResult};
+use helper::{
+    ControlledAddress, DepositAddress, KeyMigration, KeyName, KeyValue, Memory, MintRecord,
+    OutcallCost, OwnedCoin, PendingWithdrawal, Provider, SignedTransaction, SuiOrigin, SweepRecord,
+    WithdrawRecord, Withdrawal,
 };
-use ic_cdk::{api, query, update};
+use history::{Activity, HistoryPage, HistoryQuery};
+use ic_canister_log::log;
+use ic_cdk::{query, update};
 use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::NumTokens;
 use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
 use models::{
-    ExecuteTxBlockResponse, InitArgs, PublicKeyBS64, PublicKeyResponse, ResponseSizeEstimate,
-    TransferWithdrawArgs,
+    InitArgs, MinterArg, PublicKeyBS64, PublicKeyResponse, ResponseSizeEstimate,
+    TransferWithdrawArgs, UpgradeArgs,
 };
+use providers::{ProviderArgs, ProviderStatus};
+use serde_bytes::ByteBuf;
 use serde_json::{self};
 use std::str::FromStr;
+use std::time::Duration;
+mod certification;
+mod clock;
+mod coins;
 mod common;
+mod consolidation;
 mod constants;
+mod deposits;
+mod events;
+mod guard;
 mod helper;
+mod history;
 mod logs;
+mod migration;
+mod outcall;
+mod providers;
+mod signature;
+mod sui;
+mod transactions;
+mod transforms;
+mod withdrawals;
 use crate::logs::INFO;
 use base64::{self, engine::general_purpose::STANDARD, Engine};
-use candid::{candid_method, Nat, Principal};
+use candid::{Nat, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableLog};
 use std::cell::RefCell;
 pub mod models;
-use crate::models::Receipt;
+use crate::models::{NextCursor, ReceiptResultData};
 use icrc_ledger_types::icrc1::transfer::BlockIndex;
 use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
 
@@ -49,422 +75,888 @@ thread_local! {
         )
     );
 
-    static FINALIZED_TRANSACTIONS: RefCell<StableBTreeMap<KeyName, KeyValue, Memory>> = RefCell::new(
+    // History kept as JSON strings before MINT_RECORDS and WITHDRAW_RECORDS.
+    // Only holds entries the upgrade migration couldn't parse.
+    static LEGACY_FINALIZED_TRANSACTIONS: RefCell<StableBTreeMap<KeyName, KeyValue, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
         )
     );
 
-    static MINTED_TRANSACTIONS: RefCell<StableBTreeMap<KeyName, KeyValue, Memory>> = RefCell::new(
+    static LEGACY_MINTED_TRANSACTIONS: RefCell<StableBTreeMap<KeyName, KeyValue, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
         )
     );
+
+    static PENDING_WITHDRAWALS: RefCell<StableBTreeMap<u64, PendingWithdrawal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    static COINS: RefCell<StableBTreeMap<KeyName, OwnedCoin, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    static SIGNED_TRANSACTIONS: RefCell<StableBTreeMap<KeyName, SignedTransaction, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+
+    static RESERVED_OBJECTS: RefCell<StableBTreeMap<KeyName, KeyValue, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+
+    static WITHDRAWALS: RefCell<StableBTreeMap<u64, Withdrawal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    static DEPOSIT_ADDRESSES: RefCell<StableBTreeMap<KeyName, DepositAddress, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+
+    static CREDITED_DEPOSITS: RefCell<StableBTreeMap<KeyName, KeyValue, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+
+    static CONTROLLED_ADDRESSES: RefCell<StableBTreeMap<KeyName, ControlledAddress, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+
+    static SWEEPS: RefCell<StableBTreeMap<u64, SweepRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+
+    static KEY_MIGRATIONS: RefCell<StableBTreeMap<u64, KeyMigration, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    static OUTCALL_COSTS: RefCell<StableBTreeMap<KeyName, OutcallCost, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+
+    static PROVIDERS: RefCell<StableBTreeMap<KeyName, Provider, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+
+    static MINT_RECORDS: RefCell<StableBTreeMap<u64, MintRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+
+    static WITHDRAW_RECORDS: RefCell<StableBTreeMap<u64, WithdrawRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+
+    // "principal:kind:block_index" for every mint and withdraw record, see history.rs.
+    static PRINCIPAL_INDEX: RefCell<StableBTreeMap<KeyName, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    // "address:kind:block_index" for every deposit and withdraw record, see history.rs.
+    static ADDRESS_INDEX: RefCell<StableBTreeMap<KeyName, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    // "principal:block_index" for every withdrawal, see withdrawals.rs.
+    static WITHDRAWAL_INDEX: RefCell<StableBTreeMap<KeyName, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+
+    // "tx_digest:id" for every sweep, see consolidation.rs.
+    static SWEEP_DIGESTS: RefCell<StableBTreeMap<KeyName, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+
+    // Every input that changed the bridge state, see events.rs.
+    static EVENT_LOG: RefCell<StableLog<Event, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+        .expect("failed to initialize the event log")
+    );
 }
 
 fn setup_timers() {
     ic_cdk_timers::set_timer_interval(QUERY_SUI_EVENTS_INTERVAL, || ic_cdk::spawn(self::mint()));
+    ic_cdk_timers::set_timer_interval(FLUSH_WITHDRAWALS_INTERVAL, || {
+        ic_cdk::spawn(withdrawals::flush())
+    });
+    ic_cdk_timers::set_timer_interval(COIN_MAINTENANCE_INTERVAL, || {
+        ic_cdk::spawn(migration::settle());
+        ic_cdk::spawn(coins::maintain())
+    });
+    ic_cdk_timers::set_timer_interval(SCAN_DEPOSITS_INTERVAL, || ic_cdk::spawn(deposits::scan()));
+    ic_cdk_timers::set_timer_interval(CONSOLIDATION_INTERVAL, || {
+        ic_cdk::spawn(consolidation::consolidate())
+    });
 }
 
 #[ic_cdk_macros::post_upgrade]
-fn post_upgrade(args: InitArgs) {
+fn post_upgrade(arg: MinterArg) {
+    let args = match arg {
+        MinterArg::Upgrade(args) => args.unwrap_or_default(),
+        MinterArg::Init(_) => ic_cdk::trap("Cannot upgrade the minter with init arguments"),
+    };
+    // Trapping rolls the upgrade back, so a bad argument changes nothing.
+    if let Err(e) = validate_config(&args) {
+        ic_cdk::trap(&format!("Invalid upgrade arguments: {e}"));
+    }
+
+    // Seeded before anything else is appended, so that only a log that
+    // didn't exist yet gets seeded.
+    events::seed_if_empty();
+    events::record(EventType::Upgrade(args.clone()));
     setup_timers();
+    // Minters installed before the key was configurable use the default
+    // key, which follows `is_local`. It is stored before `is_local` can change.
+    if self::get(KEY_CONFIG_KEY.to_string()).is_none() {
+        store_key_config(default_key_config());
+    }
+    // Minters from before the provider registry keep using their RPC URL.
+    if providers::is_empty() {
+        if let Some(url) = self::get(LEGACY_API_URL_KEY.to_string()) {
+            if let Err(e) = providers::set(ProviderArgs {
+                name: DEFAULT_PROVIDER_NAME.to_string(),
+                url,
+                headers: None,
+            }) {
+                log!(INFO, "Ignoring the stored api_url: {e}");
+            }
+        }
+    }
     populate_memory(args);
+    // A minter upgraded from before a field existed may still lack it.
+    if let Err(e) = check_config_complete() {
+        ic_cdk::trap(&format!("Incomplete configuration after the upgrade: {e}"));
+    }
+    history::backfill_indexes();
+    history::migrate_legacy();
+    withdrawals::backfill_index();
+    consolidation::backfill_index();
+    certification::rebuild();
+    fetch_public_key();
 }
 
 #[ic_cdk_macros::init]
-fn init(args: InitArgs) {
+fn init(arg: MinterArg) {
+    let args = match arg {
+        MinterArg::Init(args) => args,
+        MinterArg::Upgrade(_) => ic_cdk::trap("Cannot install the minter with upgrade arguments"),
+    };
+    if let Err(e) = install(args) {
+        ic_cdk::trap(&e);
+    }
     setup_timers();
-    populate_memory(args);
+    fetch_public_key();
 }
 
-fn populate_memory(args: InitArgs) {
-    let InitArgs {
-        ledger_canister_id,
-        local_mgmt_principal_id,
-        api_url,
-        tx_digest_url,
-        is_local,
-        minter_address_id,
-        query_events_url,
-        execute_tx_block_url,
-    } = args;
-
-    if ledger_canister_id == ""
-        || local_mgmt_principal_id == ""
-        || api_url == ""
-        || tx_digest_url == ""
-        || is_local == ""
-        || minter_address_id == ""
-        || query_events_url == ""
-        || execute_tx_block_url == ""
-    {
-        log!(INFO, "Missing required arguments");
-        return;
-    }
-    self::insert(LEDGER_CANISTER_ID_KEY.to_string(), ledger_canister_id);
-    self::insert(
-        LOCAL_MGMT_PRINCIPAL_ID_KEY.to_string(),
-        local_mgmt_principal_id,
-    );
-    self::insert(API_URL_KEY.to_string(), api_url);
-    self::insert(TX_DIGEST_URL_KEY.to_string(), tx_digest_url);
-    self::insert(IS_LOCAL_KEY.to_string(), is_local.to_string());
+/// The part of `init` that sets up the minter state from `args`.
+fn install(args: InitArgs) -> Result<(), String> {
+    let key_config = args.key_config;
+    let config = UpgradeArgs::from(args.clone());
+    validate_config(&config).map_err(|e| format!("Invalid init arguments: {e}"))?;
+
+    events::record(EventType::Init(args));
+    populate_memory(config);
+    check_config_complete().map_err(|e| format!("Incomplete configuration: {e}"))?;
+    // The default depends on `is_local`, so it is picked once that is stored.
+    store_key_config(key_config.unwrap_or_else(default_key_config));
+    certification::rebuild();
+    Ok(())
+}
+
+/// Sets the key of a minter that has none yet. `migrate_key` changes it later.
+fn store_key_config(key: KeyConfig) {
     self::insert(
-        MINTER_ADDRESS_KEY.to_string(),
-        minter_address_id.to_string(),
+        KEY_CONFIG_KEY.to_string(),
+        serde_json::to_string(&key).unwrap(),
     );
-    self::insert(QUERY_EVENTS_URL_KEY.to_string(), query_events_url);
-    self::insert(EXECUTE_TX_BLOCK_URL_KEY.to_string(), execute_tx_block_url);
+    events::record(EventType::ConfiguredKey(key));
 }
 
-#[update]
-async fn public_key() -> Result<PublicKeyBS64, String> {
+/// Fetches the public key right after install or upgrade, since init can't
+/// call the management canister.
+fn fetch_public_key() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Err(e) = get_public_key().await {
+                log!(INFO, "Failed to fetch the public key: {e}");
+            }
+        })
+    });
+}
+
+/// Checks that the stored configuration, merged from every install and
+/// upgrade argument, has all the fields the minter needs.
+fn check_config_complete() -> Result<(), String> {
+    let required = [
+        ("ledger_canister_id", LEDGER_CANISTER_ID_KEY),
+        ("local_mgmt_principal_id", LOCAL_MGMT_PRINCIPAL_ID_KEY),
+        ("is_local", IS_LOCAL_KEY),
+        ("minter_address_id", MINTER_ADDRESS_KEY),
+        ("deposit_event_type", DEPOSIT_EVENT_TYPE_KEY),
+        ("minter_sui_address", MINTER_SUI_ADDRESS_KEY),
+    ];
+    let mut missing: Vec<&str> = required
+        .into_iter()
+        .filter(|(_, key)| self::get(key.to_string()).is_none())
+        .map(|(field, _)| field)
+        .collect();
+    if providers::is_empty() {
+        missing.push("api_url");
+    }
+    if !missing.is_empty() {
+        return Err(format!("missing {}", missing.join(", ")));
+    }
+    Ok(())
+}
+
+/// Checks every field `args` sets, before any of them is stored.
+fn validate_config(args: &UpgradeArgs) -> Result<(), String> {
+    let principals = [
+        ("ledger_canister_id", &args.ledger_canister_id),
+        ("local_mgmt_principal_id", &args.local_mgmt_principal_id),
+        ("minter_address_id", &args.minter_address_id),
+    ];
+    for (field, value) in principals {
+        if let Some(value) = value {
+            Principal::from_text(value).map_err(|e| format!("Invalid {field} {value}: {e}"))?;
+        }
+    }
+
+    if let Some(url) = &args.api_url {
+        if !providers::is_http_url(url) {
+            return Err(format!("Invalid api_url {url}: expected an http(s) url"));
+        }
+    }
+    if let Some(event_type) = &args.deposit_event_type {
+        if !sui::is_move_event_type(event_type) {
+            return Err(format!(
+                "Invalid deposit_event_type {event_type}: expected <package>::<module>::<name>"
+            ));
+        }
+    }
+
+    if let Some(is_local) = &args.is_local {
+        if is_local != "true" && is_local != "false" {
+            return Err(format!(
+                "Invalid is_local {is_local}: expected true or false"
+            ));
+        }
+    }
+    if let Some(address) = &args.minter_sui_address {
+        sui::canonical_address(address).ok_or(format!("Invalid minter_sui_address {address}"))?;
+    }
+    if args.subnet_size == Some(0) {
+        return Err("Invalid subnet_size 0".to_string());
+    }
+    Ok(())
+}
+
+/// The `MAP` entries `args` sets. `api_url` is a provider rather than an entry.
+fn config_entries(args: &UpgradeArgs) -> Vec<(&'static str, String)> {
+    let config = [
+        (LEDGER_CANISTER_ID_KEY, args.ledger_canister_id.clone()),
+        (
+            LOCAL_MGMT_PRINCIPAL_ID_KEY,
+            args.local_mgmt_principal_id.clone(),
+        ),
+        (IS_LOCAL_KEY, args.is_local.clone()),
+        (MINTER_ADDRESS_KEY, args.minter_address_id.clone()),
+        (DEPOSIT_EVENT_TYPE_KEY, args.deposit_event_type.clone()),
+        (MINTER_SUI_ADDRESS_KEY, args.minter_sui_address.clone()),
+        (
+            SUBNET_SIZE_KEY,
+            args.subnet_size.map(|size| size.to_string()),
+        ),
+    ];
+    config
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect()
+}
+
+/// Stores the fields `args` sets and leaves the others as they are.
+fn populate_memory(args: UpgradeArgs) {
+    for (key, value) in config_entries(&args) {
+        self::insert(key.to_string(), value);
+    }
+
+    // Other providers are managed through set_provider.
+    if let Some(url) = args.api_url {
+        if let Err(e) = providers::set(ProviderArgs {
+            name: DEFAULT_PROVIDER_NAME.to_string(),
+            url,
+            headers: None,
+        }) {
+            ic_cdk::trap(&format!("Invalid api_url: {e}"));
+        }
+    }
+}
+
+#[query]
+fn public_key() -> Result<PublicKeyBS64, String> {
+    let public_key = cached_public_key().ok_or("The public key has not been fetched yet")?;
     return Ok(PublicKeyBS64 {
-        public_key: get_public_key().await.unwrap().public_key_bs64,
+        sui_address: sui::address_from_public_key(signature_scheme(), &public_key.public_key)?,
+        public_key: public_key.public_key_bs64,
     });
 }
 
+/// Sui address to which `account` can send plain SUI transfers to receive ckSUI.
+/// Only the owner of `account` can request it.
+#[update]
+async fn get_deposit_address(account: Account) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() || caller != account.owner {
+        return Err("Only the account owner can request its deposit address".to_string());
+    }
+    deposits::get_or_create_address(account).await
+}
+
+fn ensure_controller() -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can call this method".to_string());
+    }
+    Ok(())
+}
+
+/// Registers a derivation path of the minter key whose address gets swept
+/// into the main address. Returns the derived Sui address.
+#[update]
+async fn register_derivation_path(
+    derivation_path: Vec<ByteBuf>,
+    label: String,
+) -> Result<String, String> {
+    ensure_controller()?;
+    let derivation_path = derivation_path.into_iter().map(|c| c.into_vec()).collect();
+    consolidation::register(derivation_path, label).await
+}
+
+#[update]
+fn unregister_derivation_path(address: String) -> Result<(), String> {
+    ensure_controller()?;
+    consolidation::unregister(address)
+}
+
+#[query]
+fn get_controlled_addresses() -> Vec<ControlledAddress> {
+    consolidation::list()
+}
+
+#[query]
+fn get_sweeps(page: u64) -> Vec<SweepRecord> {
+    consolidation::get_sweeps(page)
+}
+
+/// Adds or updates a Sui RPC provider. Its headers, e.g. API keys, are never
+/// returned by queries.
+#[update]
+fn set_provider(args: ProviderArgs) -> Result<(), String> {
+    ensure_controller()?;
+    providers::set(args)
+}
+
+#[update]
+fn remove_provider(name: String) -> Result<(), String> {
+    ensure_controller()?;
+    providers::remove(name)
+}
+
+#[query]
+fn get_providers() -> Vec<ProviderStatus> {
+    providers::list()
+}
+
+/// Cycles charged for HTTP outcalls so far, per endpoint.
+#[query]
+fn get_outcall_costs() -> Vec<(String, OutcallCost)> {
+    outcall::costs()
+}
+
+#[query]
+fn get_key_config() -> KeyConfig {
+    key_config()
+}
+
+/// Transfers all funds to the Sui address of `new_key` and switches the minter
+/// to it. Returns the migration record with both addresses. Calling it again
+/// with the same key resumes a migration that didn't finish.
+#[update]
+async fn migrate_key(new_key: KeyConfig) -> Result<KeyMigration, String> {
+    ensure_controller()?;
+    migration::migrate(new_key).await
+}
+
+#[query]
+fn get_key_migrations() -> Vec<KeyMigration> {
+    migration::list()
+}
+
+/// The Sui address of the minter key, derived from its cached public key.
+#[query]
+fn sui_address() -> Result<String, String> {
+    let public_key = cached_public_key().ok_or("The public key has not been fetched yet")?;
+    sui::address_from_public_key(signature_scheme(), &public_key.public_key)
+}
+
+/// Checks that `public_key` controls the configured `minter_sui_address`, so
+/// the minter never signs for an address it does not own.
+fn validate_sui_address(public_key: &[u8]) -> Result<(), String> {
+    let derived = sui::address_from_public_key(signature_scheme(), public_key)?;
+    let configured =
+        self::get(MINTER_SUI_ADDRESS_KEY.to_string()).ok_or("Missing minter Sui address")?;
+    if !sui::same_address(&derived, &configured) {
+        return Err(format!(
+            "Configured minter Sui address {configured} does not match the key's address {derived}"
+        ));
+    }
+    Ok(())
+}
+
 #[update]
 async fn withdraw(args: TransferWithdrawArgs) -> Result<WithdrawResponse, String> {
-    let token_minter = self::get(MINTER_ADDRESS_KEY.to_string()).unwrap();
+    let token_minter = self::get(MINTER_ADDRESS_KEY.to_string()).ok_or("Missing minter address")?;
+    let token_minter = Principal::from_text(&token_minter)
+        .map_err(|e| format!("Invalid minter address {token_minter}: {e}"))?;
+    let ledger_canister_id =
+        self::get(LEDGER_CANISTER_ID_KEY.to_string()).ok_or("Missing ledger canister id")?;
+    let ledger_canister_id = Principal::from_text(&ledger_canister_id)
+        .map_err(|e| format!("Invalid ledger canister id {ledger_canister_id}: {e}"))?;
+    let amount = u64::from_str(&args.amount)
+        .map_err(|e| format!("invalid amount {}: {}", args.amount, e))?;
+    // Checked before the ckSUI is taken, since a recipient Sui rejects could
+    // only be reimbursed.
+    let recipient = sui::canonical_address(&args.recipient)
+        .ok_or(format!("invalid recipient {}", args.recipient))?;
 
     let transfer_from_args = TransferFromArgs {
         from: Account::from(ic_cdk::caller()),
         memo: None,
-        amount: Nat::from_str(&args.amount.clone()).unwrap(),
+        amount: Nat::from(amount),
         spender_subaccount: None,
         fee: None,
         to: Account {
-            owner: Principal::from_text(token_minter).unwrap(),
+            owner: token_minter,
             subaccount: None,
         },
         created_at_time: None,
     };
 
     let result = ic_cdk::call::<(TransferFromArgs,), (Result<BlockIndex, TransferFromError>,)>(
-        Principal::from_text(self::get(LEDGER_CANISTER_ID_KEY.to_string()).unwrap()).unwrap(),
+        ledger_canister_id,
         "icrc2_transfer_from",
         (transfer_from_args,),
     )
     .await
     .map_err(|e| format!("failed to call ledger: {:?}", e))?
     .0
-    .map_err(|e| format!("ledger transfer error {:?}", e));
-
-    if result.is_err() {
-        return Err(result.unwrap_err());
-    }
+    .map_err(|e| format!("ledger transfer error {:?}", e))?;
 
-    let block_index = result.unwrap().to_string();
+    let block_index = nat_to_u64(&result).ok_or("block index overflow")?;
 
-    let public_key_response = get_public_key()
-        .await
-        .map_err(|e| format!("get_public_key failed {:?}", e));
+    let queued = withdrawals::enqueue(PendingWithdrawal {
+        block_index,
+        from: ic_cdk::caller(),
+        recipient,
+        amount,
+        created_at: clock::now(),
+    });
 
-    if public_key_response.is_err() {
-        return Err(public_key_response.unwrap_err());
+    if queued >= MAX_WITHDRAWAL_BATCH_SIZE {
+        ic_cdk::spawn(withdrawals::flush());
     }
 
-    let public_key = public_key_response.unwrap().public_key;
-
-    let request = get_withdraw_request(args.recipient, args.amount.clone());
-
-    let cycles = get_req_cycles();
-    match http_request(request, cycles).await {
-        Ok((response,)) => {
-            let result = serde_json::from_slice::<TxDigestResponse>(&response.body);
-
-            if result.is_err() {
-                return Err("Failed to get tx digest".to_string());
-            }
-
-            let TxDigestResponse { digest, tx_bytes } = result.unwrap();
-
-            let signature = encode_signature(digest.clone(), public_key.clone()).await;
-            let tx_digest = execute_tx_block_sui_rpc(signature.clone(), tx_bytes.clone())
-                .await
-                .map_err(|e| format!("execute_tx_block_sui_rpc error {}", e))?;
-            let is_local = self::get(IS_LOCAL_KEY.to_string()).unwrap();
-            let tx: String = match is_local.as_str() {
-                "true" => format!(
-                    "https://suiscan.xyz/{:}/tx/{:}",
-                    "testnet",
-                    tx_digest.clone()
-                ),
-                _ => format!(
-                    "https://suiscan.xyz/{:}/tx/{:}",
-                    "mainnet",
-                    tx_digest.clone()
-                ),
-            };
-
-            self::insert_withdraw_tx(
-                block_index.clone(),
-                format!("{{\"block_index\": \"{:}\",\"date\":\"{:}\", \"amount\": \"{:}\",\"from\": \"{:}\", \"tx\": \"{:}\" }}"
-                ,block_index.clone(), api::time().to_string(), args.amount, ic_cdk::caller().to_string(), tx
-            ));
+    return Ok(WithdrawResponse {
+        block_index: block_index.to_string(),
+    });
+}
 
-            return Ok(WithdrawResponse { tx_digest });
-        }
-        Err((r, m)) => {
-            log!(
-                INFO,
-                "The http_request resulted into error. RejectionCode: {r:?}, Error: {m}"
-            );
-            return Err(m.to_string());
-        }
-    };
+fn explorer_tx_url(tx_digest: &str) -> String {
+    let is_local = self::get(IS_LOCAL_KEY.to_string()).unwrap();
+    match is_local.as_str() {
+        "true" => format!("https://suiscan.xyz/{:}/tx/{:}", "testnet", tx_digest),
+        _ => format!("https://suiscan.xyz/{:}/tx/{:}", "mainnet", tx_digest),
+    }
 }
 
-#[candid_method(query)]
-#[query]
-fn cleanup_response(raw: TransformArgs) -> HttpResponse {
-    let headers = vec![
-        HttpHeader {
-            name: "Content-Security-Policy".to_string(),
-            value: "default-src 'self'".to_string(),
-        },
-        HttpHeader {
-            name: "Referrer-Policy".to_string(),
-            value: "strict-origin".to_string(),
-        },
-        HttpHeader {
-            name: "Permissions-Policy".to_string(),
-            value: "geolocation=(self)".to_string(),
-        },
-        HttpHeader {
-            name: "Strict-Transport-Security".to_string(),
-            value: "max-age=63072000".to_string(),
-        },
-        HttpHeader {
-            name: "X-Frame-Options".to_string(),
-            value: "DENY".to_string(),
-        },
-        HttpHeader {
-            name: "X-Content-Type-Options".to_string(),
-            value: "nosniff".to_string(),
-        },
-    ];
+fn nat_to_u64(n: &Nat) -> Option<u64> {
+    let digits = n.0.to_u64_digits();
+    match digits.len() {
+        0 => Some(0),
+        1 => Some(digits[0]),
+        _ => None,
+    }
+}
 
-    let mut res = HttpResponse {
-        status: raw.response.status.clone(),
-        body: raw.response.body.clone(),
-        headers,
-        ..Default::default()
-    };
+async fn mint() {
+    if let Err(e) = mint_events().await {
+        log!(INFO, "Failed to mint the latest events: {e}");
+    }
+}
 
-    if res.status == 200u16 {
-        res.body = raw.response.body.clone();
-    } else {
-        ic_cdk::api::print(format!("Received error: err = {:?}", raw));
+/// The Sui transaction, event and sender behind a bridge event, for the mint
+/// record. Fields that don't parse are left out, and the whole origin when the
+/// transaction digest doesn't; the mint itself never depends on them.
+fn event_origin(event: &ReceiptResultData) -> Option<SuiOrigin> {
+    if !sui::is_transaction_digest(&event.id.tx_digest) {
+        log!(
+            INFO,
+            "Minting event with invalid transaction digest {} without its origin",
+            event.id.tx_digest
+        );
+        return None;
     }
-    res
+    Some(SuiOrigin {
+        tx_digest: event.id.tx_digest.clone(),
+        event_seq: event.id.event_seq.parse::<u64>().ok(),
+        sender: sui::canonical_address(&event.sender),
+        timestamp_ms: event.timestamp_ms.parse::<u64>().ok(),
+    })
 }
 
-async fn mint() {
+/// Moves the events cursor past `event`, which is never looked at again.
+fn mark_processed(event: &ReceiptResultData) {
+    self::insert(
+        PROCESSED_TX_DIGEST_KEY.to_string(),
+        event.id.tx_digest.clone(),
+    );
+    self::insert(
+        PROCESSED_EVENT_SEQ_KEY.to_string(),
+        event.id.event_seq.clone(),
+    );
+}
+
+/// Mints the deposit events emitted since the last one minted, in order. An
+/// event that can't be minted yet stops the run, so that it is retried first.
+async fn mint_events() -> Result<(), String> {
     use icrc_ledger_client::{CdkRuntime, ICRC1Client};
     use icrc_ledger_types::icrc1::account::Account;
     use icrc_ledger_types::icrc1::transfer::TransferArg;
 
-    let context = Context {
-        bucket_start_time_index: 0,
-        closing_price_index: 4,
+    let event_type =
+        self::get(DEPOSIT_EVENT_TYPE_KEY.to_string()).ok_or("Missing deposit event type")?;
+    let cursor = self::get(PROCESSED_TX_DIGEST_KEY.to_string()).map(|tx_digest| NextCursor {
+        tx_digest,
+        // Minters from before the sequence number was kept resume after the
+        // first event of the last transaction they minted.
+        event_seq: self::get(PROCESSED_EVENT_SEQ_KEY.to_string())
+            .unwrap_or_else(|| "0".to_string()),
+    });
+    let events = sui::query_events(&event_type, cursor, EVENTS_PAGE_SIZE).await?;
+
+    let ledger_canister_id =
+        self::get(LEDGER_CANISTER_ID_KEY.to_string()).ok_or("Missing ledger canister id")?;
+    let ledger_canister_id = Principal::from_text(&ledger_canister_id)
+        .map_err(|e| format!("Invalid ledger canister id {ledger_canister_id}: {e}"))?;
+    let client = ICRC1Client {
+        runtime: CdkRuntime,
+        ledger_canister_id,
     };
-
-    let tx_digest_cursor = self::get(PROCESSED_TX_DIGEST_KEY.to_string());
-    let mut query_events = format!("{{\"tx_digest\": \"{}\" }}", "");
-    let mut tx_digest_value = "";
-
-    if tx_digest_cursor != None {
-        tx_digest_value = tx_digest_cursor.as_ref().unwrap();
-        query_events = format!("{{\"tx_digest\": \"{}\" }}", tx_digest_value);
-    }
-
-    let effective_size_estimate = get_effective_size_estimate();
-    let cycles = get_req_cycles();
-
-    let request = CanisterHttpRequestArgument {
-        url: self::get(QUERY_EVENTS_URL_KEY.to_string()).unwrap(),
-        max_response_bytes: Some(effective_size_estimate),
-        method: HttpMethod::POST,
-        headers: vec![HttpHeader {
-            name: "Content-Type".to_string(),
-            value: "application/json".to_string(),
-        }],
-        body: Some(query_events.to_string().into_bytes()),
-        transform: Some(TransformContext::from_name(
-            "cleanup_response".to_owned(),
-            serde_json::to_vec(&context).unwrap(),
-        )),
+    let canister_backend = Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
     };
 
-    match http_request(request, cycles).await {
-        Ok((response,)) => {
-            let trasnaction = serde_json::from_slice::<Receipt>(&response.body)
-                .map_err(|e| format!("Error: {}", e.to_string()));
-
-            let events = &trasnaction.clone().unwrap().result.data;
-
-            for event in events {
-                if tx_digest_value == event.id.tx_digest {
-                    break;
-                }
-
-                let parsed_json = &event.parsed_json;
-
-                let principal: Principal =
-                    Principal::from_str(&parsed_json.principal_address).unwrap();
-                let amount: NumTokens = NumTokens::from_str(&parsed_json.value).unwrap();
-
-                let ledger_canister_id: Principal =
-                    Principal::from_text(self::get(LEDGER_CANISTER_ID_KEY.to_string()).unwrap())
-                        .unwrap();
-
-                let client = ICRC1Client {
-                    runtime: CdkRuntime,
-                    ledger_canister_id,
-                };
-
-                let canister_backend = Account {
-                    owner: Principal::from_text(ic_cdk::id().to_string()).unwrap(),
-                    subaccount: None,
-                };
-
-                let balance = client.balance_of(canister_backend).await.unwrap();
-
-                if balance < amount {
-                    log!(INFO, "Not enough balance ({balance})");
-                    continue;
-                }
+    for event in &events.data {
+        let parsed_json = &event.parsed_json;
+
+        let principal = match Principal::from_str(&parsed_json.principal_address) {
+            Ok(principal) => principal,
+            Err(e) => {
+                log!(
+                    INFO,
+                    "Skipping event {}: invalid principal {}: {e}",
+                    event.id.tx_digest,
+                    parsed_json.principal_address
+                );
+                mark_processed(event);
+                continue;
+            }
+        };
+        let amount = match parsed_json.value.parse::<u64>() {
+            Ok(amount) => amount,
+            Err(e) => {
+                log!(
+                    INFO,
+                    "Skipping event {}: invalid amount {}: {e}",
+                    event.id.tx_digest,
+                    parsed_json.value
+                );
+                mark_processed(event);
+                continue;
+            }
+        };
 
-                let to: Account = Account {
-                    owner: principal,
-                    subaccount: None,
-                };
-                let block_index = match client
-                    .transfer(TransferArg {
-                        from_subaccount: None,
-                        to,
-                        fee: None,
-                        created_at_time: None,
-                        memo: None,
-                        amount,
-                    })
-                    .await
-                {
-                    Ok(Ok(block_index)) => {
-                        self::insert(
-                            PROCESSED_TX_DIGEST_KEY.to_string(),
-                            trasnaction.clone().unwrap().result.next_cursor.tx_digest,
-                        );
-
-                        self::insert_mint_tx(
-                            block_index.clone().to_string(),
-                            format!("{{\"block_index\": \"{:}\",\"date\":\"{:}\", \"amount\": \"{:}\",\"from\": \"{:}\", \"to\": \"{:}\" }}"
-                            ,block_index.clone(), api::time().to_string(), &parsed_json.value, ic_cdk::caller().to_string(), &parsed_json.principal_address
-                        ));
-                        block_index.to_string()
-                    }
-                    Ok(Err(err)) => err.to_string(),
-                    Err(err) => {
-                        log!(
-                                INFO,
-                                "Failed to send a message to the ledger ({ledger_canister_id}): {err:?}"
-                            );
-                        "error".to_string()
-                    }
-                };
+        let balance = client.balance_of(canister_backend).await.map_err(|e| {
+            format!(
+                "Failed to get the minter balance from the ledger ({ledger_canister_id}): {e:?}"
+            )
+        })?;
 
-                log!(INFO, "Minted tokens on ({block_index})");
-            }
+        // Later events wait too, so that the cursor never moves past one
+        // that wasn't minted.
+        if balance < NumTokens::from(amount) {
+            log!(
+                INFO,
+                "Not enough balance ({balance}) to mint event {}",
+                event.id.tx_digest
+            );
+            break;
         }
-        Err((r, m)) => {
+
+        let to: Account = Account {
+            owner: principal,
+            subaccount: None,
+        };
+        // The canonical sender when it is a valid address, cut short otherwise.
+        let sender = sui::canonical_address(&event.sender)
+            .unwrap_or_else(|| event.sender.chars().take(MAX_RAW_SENDER_CHARS).collect());
+        // Everything that could keep the record from being stored is checked
+        // before the transfer: a trap after it would roll back the cursor and
+        // mint the event again.
+        let mut record = MintRecord {
+            block_index: u64::MAX,
+            amount,
+            from: sender.clone(),
+            to,
+            created_at: clock::now(),
+            origin: event_origin(event),
+        };
+        if !record.fits() {
             log!(
                 INFO,
-                "The http_request resulted into error. RejectionCode: {r:?}, Error: {m}"
+                "Skipping event {}: its mint record is too large",
+                event.id.tx_digest
             );
+            mark_processed(event);
+            continue;
         }
+        events::record(EventType::ReceivedEvent {
+            tx_digest: event.id.tx_digest.clone(),
+            event_seq: event.id.event_seq.parse::<u64>().unwrap_or_default(),
+            sender,
+            amount,
+            to,
+        });
+        let block_index = match client
+            .transfer(TransferArg {
+                from_subaccount: None,
+                to,
+                fee: None,
+                created_at_time: None,
+                memo: None,
+                amount: NumTokens::from(amount),
+            })
+            .await
+        {
+            Ok(Ok(block_index)) => {
+                mark_processed(event);
+
+                // The cursor already moved past the event, so a mint that
+                // can't be recorded is only logged.
+                match nat_to_u64(&block_index) {
+                    Some(index) => {
+                        record.block_index = index;
+                        record.created_at = clock::now();
+                        history::record_mint(record);
+                    }
+                    None => log!(
+                        INFO,
+                        "Not recording the mint of event {}: block index {block_index} overflows",
+                        event.id.tx_digest
+                    ),
+                }
+                block_index.to_string()
+            }
+            Ok(Err(err)) => {
+                log!(INFO, "Failed to mint event {}: {err}", event.id.tx_digest);
+                break;
+            }
+            Err(err) => {
+                log!(
+                    INFO,
+                    "Failed to send a message to the ledger ({ledger_canister_id}): {err:?}"
+                );
+                break;
+            }
+        };
+
+        log!(INFO, "Minted tokens on ({block_index})");
+    }
+
+    Ok(())
+}
+
+/// The key used before it became configurable: the local dfx key when running
+/// locally, `key_1` otherwise, with secp256k1.
+fn default_key_config() -> KeyConfig {
+    let is_local = self::get(IS_LOCAL_KEY.to_string()).unwrap_or_default();
+    KeyConfig {
+        key_id: match is_local.as_str() {
+            "true" => EcdsaKeyIds::TestKeyLocalDevelopment,
+            _ => EcdsaKeyIds::ProductionKey1,
+        },
+        scheme: SignatureScheme::Secp256k1,
     }
 }
 
-async fn encode_signature(digest: String, public_key: Vec<u8>) -> String {
-    let digest_decoded = Engine::decode(&STANDARD, digest)
-        .map_err(|e| format!("Error: {}", e.to_string()))
-        .unwrap();
+fn key_config() -> KeyConfig {
+    self::get(KEY_CONFIG_KEY.to_string())
+        .and_then(|key_config| serde_json::from_str(&key_config).ok())
+        .unwrap_or_else(default_key_config)
+}
 
-    let digest: [u8; 32] = digest_decoded.try_into().unwrap();
-    let signature = sign_with_ecdsa(digest).await;
+fn signature_scheme() -> SignatureScheme {
+    key_config().scheme
+}
+
+async fn encode_signature(
+    key: KeyConfig,
+    digest: [u8; 32],
+    public_key: Vec<u8>,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<String, String> {
+    let scheme = key.scheme;
+    let signature = match scheme {
+        SignatureScheme::Secp256k1 => sign_with_ecdsa(key.key_id, digest, derivation_path).await?,
+        SignatureScheme::Ed25519 => sign_with_schnorr(key.key_id, digest, derivation_path).await?,
+    };
+    let signature = signature::normalize(scheme, digest, &public_key, &signature)
+        .map_err(|e| format!("Invalid signature: {e}"))?;
 
-    let flag: u8 = 0x1;
+    let flag: u8 = scheme.flag();
     let mut signature_bytes: Vec<u8> = Vec::new();
     signature_bytes.extend_from_slice(&[flag]);
     signature_bytes.extend_from_slice(&signature.as_ref());
     signature_bytes.extend_from_slice(&public_key.as_ref());
 
     let signature_encoded = Engine::encode(&STANDARD, &signature_bytes[..]);
-    return signature_encoded;
+    return Ok(signature_encoded);
 }
 
-fn get_withdraw_request(recipient: String, amount: String) -> CanisterHttpRequestArgument {
-    let context = Context {
-        bucket_start_time_index: 0,
-        closing_price_index: 4,
-    };
+/// Public key of the minter's main Sui address, derived with the empty path.
+/// It is fetched once per key config and then served from stable memory.
+async fn get_public_key() -> Result<PublicKeyResponse, String> {
+    if let Some(public_key) = cached_public_key() {
+        return Ok(public_key);
+    }
+
+    let key = key_config();
+    let public_key = derive_public_key(key, vec![]).await?;
+    cache_public_key(key, &public_key);
+    if let Err(e) = validate_sui_address(&public_key.public_key) {
+        log!(INFO, "{e}");
+    }
+    Ok(public_key)
+}
 
-    let tx_digest_json = format!(
-        "{{\"recipient\": \"{}\", \"amount\": \"{}\" }}",
-        recipient, amount
+/// The cached public key, unless it was fetched for a different key config.
+fn cached_public_key() -> Option<PublicKeyResponse> {
+    let cached_for: KeyConfig =
+        serde_json::from_str(&self::get(PUBLIC_KEY_CONFIG_KEY.to_string())?).ok()?;
+    if cached_for != key_config() {
+        return None;
+    }
+    let public_key_bs64 = self::get(PUBLIC_KEY_KEY.to_string())?;
+    Some(PublicKeyResponse {
+        public_key: Engine::decode(&STANDARD, &public_key_bs64).ok()?,
+        public_key_bs64,
+    })
+}
+
+fn cache_public_key(key: KeyConfig, public_key: &PublicKeyResponse) {
+    self::insert(
+        PUBLIC_KEY_KEY.to_string(),
+        public_key.public_key_bs64.clone(),
+    );
+    self::insert(
+        PUBLIC_KEY_CONFIG_KEY.to_string(),
+        serde_json::to_string(&key).unwrap(),
     );
-    let request_body: Option<Vec<u8>> = Some(tx_digest_json.to_string().into_bytes());
-    let effective_size_estimate = get_effective_size_estimate();
-    let request = CanisterHttpRequestArgument {
-        url: self::get(TX_DIGEST_URL_KEY.to_string()).unwrap(),
-        max_response_bytes: Some(effective_size_estimate),
-        method: HttpMethod::POST,
-        headers: vec![HttpHeader {
-            name: "Content-Type".to_string(),
-            value: "application/json".to_string(),
-        }],
-        body: request_body,
-        transform: Some(TransformContext::from_name(
-            "cleanup_response".to_owned(),
-            serde_json::to_vec(&context).unwrap(),
-        )),
-    };
-    return request;
 }
 
-async fn get_public_key() -> Result<PublicKeyResponse, String> {
-    let is_local = self::get(IS_LOCAL_KEY.to_string()).unwrap();
-    let request = ECDSAPublicKey {
-        canister_id: None,
-        derivation_path: vec![],
-        key_id: match is_local.as_str() {
-            "true" => EcdsaKeyIds::TestKeyLocalDevelopment.to_key_id(),
-            _ => EcdsaKeyIds::ProductionKey1.to_key_id(),
-        },
+async fn derive_public_key(
+    key: KeyConfig,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<PublicKeyResponse, String> {
+    let public_key = match key.scheme {
+        SignatureScheme::Secp256k1 => {
+            let request = ECDSAPublicKey {
+                canister_id: None,
+                derivation_path: derivation_path.clone(),
+                key_id: key.key_id.to_key_id(),
+            };
+            let (res_public_key,): (ECDSAPublicKeyReply,) =
+                ic_cdk::call(mgmt_canister_id(), "ecdsa_public_key", (request,))
+                    .await
+                    .map_err(|e| format!("ecdsa_public_key failed {}", e.1))?;
+            res_public_key.public_key
+        }
+        SignatureScheme::Ed25519 => {
+            let request = SchnorrPublicKey {
+                canister_id: None,
+                derivation_path,
+                key_id: key.key_id.to_schnorr_key_id(),
+            };
+            let (res_public_key,): (SchnorrPublicKeyReply,) =
+                ic_cdk::call(mgmt_canister_id(), "schnorr_public_key", (request,))
+                    .await
+                    .map_err(|e| format!("schnorr_public_key failed {}", e.1))?;
+            res_public_key.public_key
+        }
     };
-    let (res_public_key,): (ECDSAPublicKeyReply,) =
-        ic_cdk::call(mgmt_canister_id(), "ecdsa_public_key", (request,))
-            .await
-            .map_err(|e| format!("ecdsa_public_key failed {}", e.1))?;
 
     Ok(PublicKeyResponse {
-        public_key: res_public_key.public_key.clone(),
-        public_key_bs64: Engine::encode(&STANDARD, &res_public_key.public_key.clone()),
+        public_key_bs64: Engine::encode(&STANDARD, &public_key),
+        public_key,
     })
 }
 
-async fn sign_with_ecdsa(digest: [u8; 32]) -> Vec<u8> {
-    let is_local = self::get(IS_LOCAL_KEY.to_string()).unwrap();
+async fn sign_with_ecdsa(
+    key_id: EcdsaKeyIds,
+    digest: [u8; 32],
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
     let request = SignWithECDSA {
         message_hash: sha256(digest).to_vec(),
-        derivation_path: vec![],
-        key_id: match is_local.as_str() {
-            "true" => EcdsaKeyIds::TestKeyLocalDevelopment.to_key_id(),
-            _ => EcdsaKeyIds::ProductionKey1.to_key_id(),
-        },
+        derivation_path,
+        key_id: key_id.to_key_id(),
     };
 
     let cycles = 30_000_000_000;
@@ -475,82 +967,42 @@ async fn sign_with_ecdsa(digest: [u8; 32]) -> Vec<u8> {
         cycles,
     )
     .await
-    .map_err(|e| format!("sign_with_ecdsa failed {}", e.1))
-    .unwrap();
+    .map_err(|e| format!("sign_with_ecdsa failed {}", e.1))?;
 
-    return response.signature;
+    Ok(response.signature)
 }
 
-async fn execute_tx_block_sui_rpc(signature: String, tx_bytes: String) -> Result<String, String> {
-    let context = Context {
-        bucket_start_time_index: 0,
-        closing_price_index: 4,
+/// Ed25519 signs the intent digest itself, without hashing it again.
+async fn sign_with_schnorr(
+    key_id: EcdsaKeyIds,
+    digest: [u8; 32],
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    let request = SignWithSchnorr {
+        message: digest.to_vec(),
+        derivation_path,
+        key_id: key_id.to_schnorr_key_id(),
     };
 
-    let request_json: String = format!(
-        "{{\"signature\": \"{}\",\"tx_bytes\":\"{}\"}}",
-        signature, tx_bytes
+    let cycles = 30_000_000_000;
+    let (response,): (SignWithSchnorrReply,) = ic_cdk::api::call::call_with_payment128(
+        mgmt_canister_id(),
+        "sign_with_schnorr",
+        (request,),
+        cycles,
     )
-    .to_string();
-
-    let effective_size_estimate = get_effective_size_estimate();
-    let cycles = get_req_cycles();
-    let request = CanisterHttpRequestArgument {
-        url: self::get(EXECUTE_TX_BLOCK_URL_KEY.to_string()).unwrap(),
-        max_response_bytes: Some(effective_size_estimate),
-        method: HttpMethod::POST,
-        headers: vec![HttpHeader {
-            name: "Content-Type".to_string(),
-            value: "application/json".to_string(),
-        }],
-        body: Some(request_json.to_string().into_bytes()),
-        transform: Some(TransformContext::from_name(
-            "cleanup_response".to_owned(),
-            serde_json::to_vec(&context).unwrap(),
-        )),
-    };
-
-    match http_request(request, cycles).await {
-        Ok((response,)) => {
-            let resp = serde_json::from_slice::<ExecuteTxBlockResponse>(&response.body)
-                .map_err(|e| format!("Error: {}", e.to_string()));
-
-            if resp.is_err() {
-                return Err("Failed to get tx digest".to_string());
-            }
-
-            return Ok(resp.unwrap().result.digest);
-        }
+    .await
+    .map_err(|e| format!("sign_with_schnorr failed {}", e.1))?;
 
-        Err((r, m)) => {
-            log!(
-                INFO,
-                "The http_request resulted into error. RejectionCode: {r:?}, Error: {m}"
-            );
-            return Err(m.to_string());
-        }
-    };
+    Ok(response.signature)
 }
 
-fn get_effective_size_estimate() -> u64 {
-    let response_size_estimate = ResponseSizeEstimate::new(256);
+fn get_effective_size_estimate(response_size_estimate: ResponseSizeEstimate) -> u64 {
     const HEADER_SIZE_LIMIT: u64 = 2 * 1024;
     let effective_size_estimate = response_size_estimate.get() + HEADER_SIZE_LIMIT;
     return effective_size_estimate;
 }
 
-fn get_req_cycles() -> u128 {
-    let effective_size_estimate = get_effective_size_estimate();
-    // Details of the values used in the following lines can be found here:
-    // https://internetcomputer.org/docs/current/developer-docs/production/computation-and-storage-costs
-    let base_cycles = 400_000_000u128 + 100_000u128 * (2 * effective_size_estimate as u128);
-
-    const BASE_SUBNET_SIZE: u128 = 13;
-    const SUBNET_SIZE: u128 = 34;
-    let cycles = base_cycles * SUBNET_SIZE / BASE_SUBNET_SIZE;
-    return cycles;
-}
-
 fn sha256(input: [u8; 32]) -> [u8; 32] {
     use sha2::Digest;
     let mut hasher = sha2::Sha256::new();
@@ -577,38 +1029,37 @@ fn insert(key: String, value: String) -> Option<String> {
         .map(|v| v.0)
 }
 
-fn insert_mint_tx(key: String, value: String) -> Option<String> {
-    MINTED_TRANSACTIONS
-        .with(|p| p.borrow_mut().insert(KeyName(key), KeyValue(value)))
-        .map(|v| v.0)
+#[query]
+fn get_minted_transactions(query: HistoryQuery) -> Certified<HistoryPage<MintRecord>> {
+    certification::mints(&query, history::minted(query.clone()))
 }
 
-fn insert_withdraw_tx(key: String, value: String) -> Option<String> {
-    FINALIZED_TRANSACTIONS
-        .with(|p| p.borrow_mut().insert(KeyName(key), KeyValue(value)))
-        .map(|v| v.0)
+#[query]
+fn get_finalized_transactions(query: HistoryQuery) -> Certified<HistoryPage<WithdrawRecord>> {
+    certification::withdraws(&query, history::finalized(query.clone()))
 }
 
 #[query]
-fn get_minted_transactions() -> Vec<KeyValue> {
-    MINTED_TRANSACTIONS.with(|transactions| {
-        return transactions
-            .borrow()
-            .iter()
-            .into_iter()
-            .map(|(_, value)| value)
-            .collect();
-    })
+fn get_principal_activity(principal: Principal, query: HistoryQuery) -> Activity {
+    history::principal_activity(principal, query)
 }
 
 #[query]
-fn get_finalized_transactions() -> Vec<KeyValue> {
-    FINALIZED_TRANSACTIONS.with(|transactions| {
-        return transactions
-            .borrow()
-            .iter()
-            .into_iter()
-            .map(|(_, value)| value)
-            .collect();
-    })
+fn get_address_activity(address: String, query: HistoryQuery) -> Result<Activity, String> {
+    history::address_activity(&address, query)
+}
+
+#[query]
+fn get_events(arg: GetEventsArg) -> GetEventsResult {
+    events::get(arg)
+}
+
+#[query]
+fn get_withdrawal_status(block_index: u64) -> Certified<Option<WithdrawalStatus>> {
+    certification::withdrawal_status(block_index, withdrawals::get_status(block_index))
+}
+
+#[query]
+fn get_withdrawals_for(principal: Principal, page: u64) -> Vec<WithdrawalStatus> {
+    withdrawals::get_for(principal, page)
 }
@@ -45,7 +45,7 @@ impl FromStr for Priority {
     }
 }
 
-#[derive(Clone, serde::Serialize, Deserialize, Debug, Copy)]
+#[derive(Clone, candid::CandidType, serde::Serialize, Deserialize, Debug, Copy)]
 pub enum Sort {
     Ascending,
     Descending,
@@ -0,0 +1,214 @@
+use crate::common::KeyConfig;
+use crate::constants::{
+    COIN_MAINTENANCE_TASK, CONSOLIDATION_TASK, KEY_CONFIG_KEY, KEY_MIGRATION_TASK, MAX_INPUT_COINS,
+    MINTER_SUI_ADDRESS_KEY, SWEEP_GAS_BUDGET, WITHDRAWALS_TASK,
+};
+use crate::events::{self, EventType};
+use crate::guard::TaskGuard;
+use crate::helper::{KeyMigration, KeyName, MigrationState};
+use crate::logs::INFO;
+use crate::models::PublicKeyResponse;
+use crate::{clock, coins, sui, transactions, COINS, KEY_MIGRATIONS};
+use ic_canister_log::log;
+
+/// Moves the minter to `new_key`: transfers all SUI from the current address
+/// to the address of the new key and then switches both the key and the
+/// address in one step. Every attempt is recorded, with both addresses.
+///
+/// A migration left in progress, e.g. by an upgrade during a transfer, is
+/// resumed by calling this again with the same key; other keys are refused
+/// until it completes.
+pub async fn migrate(new_key: KeyConfig) -> Result<KeyMigration, String> {
+    let old_key = crate::key_config();
+    if old_key == new_key {
+        return Err("The minter already uses this key".to_string());
+    }
+
+    // Nothing else may sign for the minter address while its funds move.
+    let _guards = [
+        KEY_MIGRATION_TASK,
+        WITHDRAWALS_TASK,
+        COIN_MAINTENANCE_TASK,
+        CONSOLIDATION_TASK,
+    ]
+    .into_iter()
+    .map(|task| TaskGuard::new(task).ok_or(format!("Task {task} is running, try again later")))
+    .collect::<Result<Vec<TaskGuard>, String>>()?;
+    settle_outstanding().await;
+    if transactions::has_outstanding() {
+        return Err("Signed transactions are still outstanding, try again later".to_string());
+    }
+
+    let old_address =
+        crate::get(MINTER_SUI_ADDRESS_KEY.to_string()).ok_or("Missing minter Sui address")?;
+    let new_public_key = crate::derive_public_key(new_key, vec![]).await?;
+    let new_address = sui::address_from_public_key(new_key.scheme, &new_public_key.public_key)?;
+
+    let mut migration = match in_progress() {
+        Some(migration) if migration.new_key != new_key => {
+            return Err(format!(
+                "Migration {} to {:?} is in progress, resume it with the same key",
+                migration.id, migration.new_key
+            ));
+        }
+        Some(migration) => {
+            log!(INFO, "Resuming key migration {}", migration.id);
+            migration
+        }
+        None => {
+            let id = KEY_MIGRATIONS.with(|migrations| {
+                migrations
+                    .borrow()
+                    .last_key_value()
+                    .map_or(0, |(id, _)| id + 1)
+            });
+            KeyMigration {
+                id,
+                old_key,
+                new_key,
+                old_address: old_address.clone(),
+                new_address: new_address.clone(),
+                tx_digests: vec![],
+                state: MigrationState::InProgress,
+                error: None,
+                started_at: clock::now(),
+                completed_at: None,
+            }
+        }
+    };
+    save(&migration);
+
+    match transfer_all(&old_address, &new_address, &mut migration).await {
+        Ok(()) => {
+            switch(&migration, &new_public_key);
+            migration.state = MigrationState::Completed;
+            migration.completed_at = Some(clock::now());
+            save(&migration);
+            log!(
+                INFO,
+                "Migrated the minter from {old_address} to {new_address} ({:?})",
+                new_key
+            );
+            Ok(migration)
+        }
+        Err(e) => {
+            migration.state = MigrationState::Failed;
+            migration.error = Some(e.clone());
+            migration.completed_at = Some(clock::now());
+            save(&migration);
+            Err(e)
+        }
+    }
+}
+
+/// Resubmits the transfers a failed call or an upgrade left outstanding, so
+/// they release their coins and don't block the next migration.
+pub async fn settle() {
+    let _guard = match TaskGuard::new(KEY_MIGRATION_TASK) {
+        Some(guard) => guard,
+        None => return,
+    };
+    settle_outstanding().await;
+}
+
+async fn settle_outstanding() {
+    for (_, result) in transactions::retry_outstanding(KEY_MIGRATION_TASK).await {
+        match result {
+            Ok(executed) => match executed.failure() {
+                Some(e) => log!(INFO, "Migration transfer {} failed: {e}", executed.digest),
+                None => log!(INFO, "Migration transfer {} executed", executed.digest),
+            },
+            Err(e) => log!(INFO, "Signed migration transfer is still outstanding: {e}"),
+        }
+    }
+}
+
+/// The migration an earlier call left in progress, if any.
+fn in_progress() -> Option<KeyMigration> {
+    KEY_MIGRATIONS.with(|migrations| {
+        migrations
+            .borrow()
+            .last_key_value()
+            .map(|(_, migration)| migration)
+            .filter(|migration| migration.state == MigrationState::InProgress)
+    })
+}
+
+/// Sends every SUI coin of the old address to the new one, in chunks of at
+/// most MAX_INPUT_COINS coins. Coins an earlier attempt already moved are
+/// gone from the old address, so a resumed migration only sends the rest.
+async fn transfer_all(
+    old_address: &str,
+    new_address: &str,
+    migration: &mut KeyMigration,
+) -> Result<(), String> {
+    coins::refresh().await?;
+    let input_coins: Vec<String> = coins::list()
+        .into_iter()
+        .map(|coin| coin.object_id)
+        .collect();
+
+    for chunk in input_coins.chunks(MAX_INPUT_COINS as usize) {
+        let tx = sui::pay_all_sui(
+            old_address,
+            chunk.to_vec(),
+            new_address.to_string(),
+            SWEEP_GAS_BUDGET,
+        )
+        .await?;
+        migration
+            .tx_digests
+            .push(sui::transaction_digest(&tx.tx_bytes)?);
+        save(migration);
+
+        let executed = transactions::sign_and_execute(KEY_MIGRATION_TASK, tx, vec![]).await?;
+        if let Some(e) = executed.failure() {
+            return Err(format!("Transfer {} failed: {e}", executed.digest));
+        }
+    }
+    Ok(())
+}
+
+/// Switches the key, the address and everything cached for the old key
+/// without awaiting in between, so no message sees a mix of both.
+pub fn switch(migration: &KeyMigration, new_public_key: &PublicKeyResponse) {
+    events::record(EventType::SwitchedKey {
+        migration_id: migration.id,
+        key: migration.new_key,
+        minter_sui_address: migration.new_address.clone(),
+    });
+    crate::insert(
+        KEY_CONFIG_KEY.to_string(),
+        serde_json::to_string(&migration.new_key).unwrap(),
+    );
+    crate::insert(
+        MINTER_SUI_ADDRESS_KEY.to_string(),
+        migration.new_address.clone(),
+    );
+    crate::cache_public_key(migration.new_key, new_public_key);
+    COINS.with(|coins| {
+        let mut coins = coins.borrow_mut();
+        let stale: Vec<KeyName> = coins.iter().map(|(object_id, _)| object_id).collect();
+        for object_id in stale {
+            coins.remove(&object_id);
+        }
+    });
+}
+
+fn save(migration: &KeyMigration) {
+    KEY_MIGRATIONS.with(|migrations| {
+        migrations
+            .borrow_mut()
+            .insert(migration.id, migration.clone())
+    });
+}
+
+pub fn list() -> Vec<KeyMigration> {
+    KEY_MIGRATIONS.with(|migrations| {
+        migrations
+            .borrow()
+            .iter()
+            .map(|(_, migration)| migration)
+            .collect()
+    })
+}
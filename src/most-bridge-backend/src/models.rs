@@ -3,6 +3,7 @@ use serde_derive::Deserialize;
 use serde_derive::Serialize;
 use std::fmt;
 
+use crate::common::KeyConfig;
 use crate::constants::MAX_PAYLOAD_SIZE;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -26,7 +27,7 @@ pub struct Receipt {
 #[serde(rename_all = "camelCase")]
 pub struct ReceiptResult {
     pub data: Vec<ReceiptResultData>,
-    pub next_cursor: NextCursor,
+    pub next_cursor: Option<NextCursor>,
     pub has_next_page: bool,
 }
 
@@ -115,6 +116,98 @@ pub struct MergeCoinsRequest {
     pub secundary_coin: String,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoinPage {
+    pub data: Vec<SuiCoin>,
+    pub next_cursor: Option<String>,
+    pub has_next_page: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SuiCoin {
+    pub coin_type: String,
+    pub coin_object_id: String,
+    pub version: String,
+    pub digest: String,
+    pub balance: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SuiTransactionBlockPage {
+    pub data: Vec<SuiTransactionBlock>,
+    pub next_cursor: Option<String>,
+    pub has_next_page: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SuiTransactionBlock {
+    pub digest: String,
+    pub balance_changes: Option<Vec<BalanceChange>>,
+    pub checkpoint: Option<String>,
+    pub timestamp_ms: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BalanceChange {
+    pub owner: serde_json::Value,
+    pub coin_type: String,
+    pub amount: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SuiObjectResponse {
+    pub data: Option<SuiObjectData>,
+    pub error: Option<SuiObjectError>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SuiObjectData {
+    pub object_id: String,
+    pub version: String,
+    pub digest: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SuiObjectError {
+    pub code: String,
+}
+
+impl SuiObjectResponse {
+    /// Returns true if the object was deleted or is at a version after `version`.
+    pub fn is_past(&self, version: u64) -> bool {
+        match (&self.data, &self.error) {
+            (Some(data), _) => data
+                .version
+                .parse::<u64>()
+                .map_or(false, |current| current > version),
+            (None, Some(error)) => error.code == "deleted",
+            (None, None) => false,
+        }
+    }
+}
+
+/// A JSON-RPC response, which carries either a `result` or an `error`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcResponse<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TxDigestRequest {
     pub recipient: String,
@@ -153,6 +246,38 @@ pub struct ExecuteTxBlockResponse {
 #[serde(rename_all = "camelCase")]
 pub struct ExecuteTxBlockResponseResult {
     pub digest: String,
+    pub effects: Option<TxEffects>,
+    pub checkpoint: Option<String>,
+}
+
+impl ExecuteTxBlockResponseResult {
+    /// Returns the reason the transaction failed on chain, if it did.
+    pub fn failure(&self) -> Option<String> {
+        match &self.effects {
+            Some(effects) if effects.status.status == "success" => None,
+            Some(effects) => Some(
+                effects
+                    .status
+                    .error
+                    .clone()
+                    .unwrap_or(effects.status.status.clone()),
+            ),
+            None => Some("missing effects".to_string()),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TxEffects {
+    pub status: TxExecutionStatus,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TxExecutionStatus {
+    pub status: String,
+    pub error: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -164,6 +289,7 @@ pub struct PublicKeyResponse {
 #[derive(CandidType, Serialize, Debug)]
 pub struct PublicKeyBS64 {
     pub public_key: String,
+    pub sui_address: String,
 }
 
 #[derive(CandidType, Deserialize, Serialize)]
@@ -172,16 +298,55 @@ pub struct TransferWithdrawArgs {
     pub recipient: String,
 }
 
-#[derive(CandidType, Deserialize, Serialize)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct InitArgs {
     pub ledger_canister_id: String,
     pub local_mgmt_principal_id: String,
     pub api_url: String,
-    pub tx_digest_url: String,
     pub is_local: String,
     pub minter_address_id: String,
-    pub query_events_url: String,
-    pub execute_tx_block_url: String,
+    /// Required, optional only so that install events from before it existed
+    /// still decode.
+    pub deposit_event_type: Option<String>,
+    pub minter_sui_address: String,
+    pub key_config: Option<KeyConfig>,
+    pub subnet_size: Option<u64>,
+}
+
+/// Configuration to change on upgrade. Fields that are not set keep their
+/// value. The key can't be changed this way, see `migrate_key`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UpgradeArgs {
+    pub ledger_canister_id: Option<String>,
+    pub local_mgmt_principal_id: Option<String>,
+    pub api_url: Option<String>,
+    pub is_local: Option<String>,
+    pub minter_address_id: Option<String>,
+    pub deposit_event_type: Option<String>,
+    pub minter_sui_address: Option<String>,
+    pub subnet_size: Option<u64>,
+}
+
+impl From<InitArgs> for UpgradeArgs {
+    fn from(args: InitArgs) -> Self {
+        Self {
+            ledger_canister_id: Some(args.ledger_canister_id),
+            local_mgmt_principal_id: Some(args.local_mgmt_principal_id),
+            api_url: Some(args.api_url),
+            is_local: Some(args.is_local),
+            minter_address_id: Some(args.minter_address_id),
+            deposit_event_type: args.deposit_event_type,
+            minter_sui_address: Some(args.minter_sui_address),
+            subnet_size: args.subnet_size,
+        }
+    }
+}
+
+/// Argument of both `init` and `post_upgrade`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum MinterArg {
+    Init(InitArgs),
+    Upgrade(Option<UpgradeArgs>),
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
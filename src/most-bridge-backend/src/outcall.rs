@@ -0,0 +1,291 @@
+use crate::constants::{
+    DEFAULT_SUBNET_SIZE, MAX_PAYLOAD_SIZE, OUTCALL_INITIAL_BACKOFF, OUTCALL_MAX_ATTEMPTS,
+    OUTCALL_MAX_BACKOFF, RESPONSE_SIZE_ESTIMATE_KEY_PREFIX, SUBNET_SIZE_KEY,
+};
+use crate::helper::{KeyName, OutcallCost};
+use crate::logs::INFO;
+use crate::models::{JsonRpcResponse, ResponseSizeEstimate};
+use crate::OUTCALL_COSTS;
+use ic_canister_log::log;
+use ic_cdk::api::call::{msg_cycles_refunded128, CallResult, RejectionCode};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpResponse,
+};
+use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// Why an outcall failed, so callers can tell failures worth retrying from
+/// the ones that will fail the same way again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutcallError {
+    /// The management canister rejected the request.
+    Rejected {
+        code: RejectionCode,
+        message: String,
+    },
+    /// The server answered with a non-2xx status.
+    HttpStatus { status: u64, body: String },
+    /// The server answered with a JSON-RPC error object.
+    JsonRpc { code: i64, message: String },
+    /// The response body doesn't have the expected shape.
+    Parse(String),
+    /// There is no server to send the request to.
+    NoProvider,
+}
+
+impl OutcallError {
+    /// SysTransient rejections, 429 and 5xx are expected to clear up on their own.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            OutcallError::Rejected { code, .. } => *code == RejectionCode::SysTransient,
+            OutcallError::HttpStatus { status, .. } => {
+                *status == 429 || (500..600).contains(status)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for OutcallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutcallError::Rejected { code, message } => {
+                write!(f, "RejectionCode: {:?}, Error: {}", code, message)
+            }
+            OutcallError::HttpStatus { status, body } => {
+                write!(f, "HTTP status {}: {}", status, body)
+            }
+            OutcallError::JsonRpc { code, message } => {
+                write!(f, "JSON-RPC error {}: {}", code, message)
+            }
+            OutcallError::Parse(message) => write!(f, "Unexpected response: {}", message),
+            OutcallError::NoProvider => write!(f, "No Sui RPC provider is registered"),
+        }
+    }
+}
+
+impl From<OutcallError> for String {
+    fn from(error: OutcallError) -> Self {
+        error.to_string()
+    }
+}
+
+fn estimate_key(endpoint: &str) -> String {
+    format!("{}{}", RESPONSE_SIZE_ESTIMATE_KEY_PREFIX, endpoint)
+}
+
+/// The response size estimate learned for `endpoint`, if any call to it ever
+/// needed more than its default.
+fn stored_estimate(endpoint: &str) -> Option<ResponseSizeEstimate> {
+    crate::get(estimate_key(endpoint))
+        .and_then(|estimate| estimate.parse::<u64>().ok())
+        .filter(|estimate| *estimate > 0 && *estimate <= MAX_PAYLOAD_SIZE)
+        .map(ResponseSizeEstimate::new)
+}
+
+fn store_estimate(endpoint: &str, estimate: ResponseSizeEstimate) {
+    crate::insert(estimate_key(endpoint), estimate.get().to_string());
+}
+
+fn subnet_size() -> u128 {
+    crate::get(SUBNET_SIZE_KEY.to_string())
+        .and_then(|size| size.parse::<u128>().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_SUBNET_SIZE)
+}
+
+/// Size of the request as the IC prices it: URL, headers, body, transform
+/// method name and context.
+fn request_size(request: &CanisterHttpRequestArgument) -> u128 {
+    let headers: usize = request
+        .headers
+        .iter()
+        .map(|header| header.name.len() + header.value.len())
+        .sum();
+    let body = request.body.as_ref().map_or(0, |body| body.len());
+    let transform = request.transform.as_ref().map_or(0, |transform| {
+        transform.function.0.method.len() + transform.context.len()
+    });
+    (request.url.len() + headers + body + transform) as u128
+}
+
+/// Cycles the IC charges for `request` on a subnet of `subnet_size` nodes:
+/// (3M + 60K * n) * n for the call, plus 400 * n per request byte and
+/// 800 * n per byte of `max_response_bytes`.
+/// https://internetcomputer.org/docs/current/developer-docs/gas-cost
+fn http_request_cycles(request: &CanisterHttpRequestArgument, subnet_size: u128) -> u128 {
+    let response_bytes = request.max_response_bytes.unwrap_or(2_000_000) as u128;
+    (3_000_000 + 60_000 * subnet_size) * subnet_size
+        + 400 * subnet_size * request_size(request)
+        + 800 * subnet_size * response_bytes
+}
+
+fn record_cost(endpoint: &str, cycles_charged: u128) {
+    OUTCALL_COSTS.with(|costs| {
+        let mut costs = costs.borrow_mut();
+        let key = KeyName(endpoint.to_string());
+        let mut cost = costs.get(&key).unwrap_or_default();
+        cost.calls += 1;
+        cost.cycles_charged = cost.cycles_charged.saturating_add(cycles_charged);
+        costs.insert(key, cost);
+    });
+}
+
+pub fn costs() -> Vec<(String, OutcallCost)> {
+    OUTCALL_COSTS.with(|costs| {
+        costs
+            .borrow()
+            .iter()
+            .map(|(endpoint, cost)| (endpoint.0, cost))
+            .collect()
+    })
+}
+
+/// The IC rejects responses larger than `max_response_bytes` with SysFatal.
+fn is_response_too_large(code: &RejectionCode, message: &str) -> bool {
+    *code == RejectionCode::SysFatal
+        && (message.contains("size limit") || message.contains("length limit"))
+}
+
+/// Sends `request`, starting from the larger of `default_estimate` and the
+/// estimate learned for `endpoint`. A response rejected for exceeding
+/// `max_response_bytes` is retried with a doubled estimate, which is kept for
+/// later calls to the same endpoint.
+async fn http_request_adaptive(
+    endpoint: &str,
+    mut request: CanisterHttpRequestArgument,
+    default_estimate: ResponseSizeEstimate,
+) -> CallResult<(HttpResponse,)> {
+    let mut estimate =
+        stored_estimate(endpoint).map_or(default_estimate, |stored| stored.max(default_estimate));
+
+    loop {
+        let effective_size_estimate = crate::get_effective_size_estimate(estimate);
+        request.max_response_bytes = Some(effective_size_estimate);
+        let cycles = http_request_cycles(&request, subnet_size());
+
+        let result = http_request(request.clone(), cycles).await;
+        record_cost(endpoint, cycles.saturating_sub(msg_cycles_refunded128()));
+        match result {
+            Err((code, message))
+                if is_response_too_large(&code, &message) && estimate.get() < MAX_PAYLOAD_SIZE =>
+            {
+                estimate = estimate.adjust();
+                store_estimate(endpoint, estimate);
+                log!(
+                    INFO,
+                    "The {endpoint} response exceeded {effective_size_estimate} bytes, retrying with an estimate of {estimate}"
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends `request` through `http_request_adaptive` and classifies the
+/// outcome. Transient failures are retried with exponential backoff, up to
+/// OUTCALL_MAX_ATTEMPTS attempts; permanent ones are returned right away.
+pub async fn http_request_with_retry(
+    endpoint: &str,
+    request: CanisterHttpRequestArgument,
+    default_estimate: ResponseSizeEstimate,
+) -> Result<HttpResponse, OutcallError> {
+    let mut backoff = OUTCALL_INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        let error = match http_request_adaptive(endpoint, request.clone(), default_estimate).await {
+            Ok((response,)) => {
+                let status = crate::nat_to_u64(&response.status).unwrap_or_default();
+                if (200..300).contains(&status) {
+                    return Ok(response);
+                }
+                OutcallError::HttpStatus {
+                    status,
+                    body: String::from_utf8_lossy(&response.body).to_string(),
+                }
+            }
+            Err((code, message)) => OutcallError::Rejected { code, message },
+        };
+
+        if !error.is_transient() || attempt >= OUTCALL_MAX_ATTEMPTS {
+            return Err(error);
+        }
+        log!(
+            INFO,
+            "Attempt {attempt} of {endpoint} failed ({error}), retrying in {}s",
+            backoff.as_secs()
+        );
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(OUTCALL_MAX_BACKOFF);
+        attempt += 1;
+    }
+}
+
+/// Decodes the body of a successful `endpoint` response as a JSON-RPC
+/// envelope, returning its `result` or the error the server answered with.
+pub fn decode_json_rpc<T: DeserializeOwned>(
+    endpoint: &str,
+    body: &[u8],
+) -> Result<T, OutcallError> {
+    let response = serde_json::from_slice::<JsonRpcResponse<T>>(body)
+        .map_err(|e| OutcallError::Parse(format!("{} response: {}", endpoint, e)))?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(OutcallError::JsonRpc {
+            code: error.code,
+            message: error.message,
+        }),
+        (Some(result), None) => Ok(result),
+        (None, None) => Err(OutcallError::Parse(format!(
+            "{} response has neither a result nor an error",
+            endpoint
+        ))),
+    }
+}
+
+#[derive(Default)]
+struct SleepState {
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+/// A future that resolves once a timer set for the given delay fires.
+struct Sleep {
+    state: Rc<RefCell<SleepState>>,
+}
+
+fn sleep(delay: Duration) -> Sleep {
+    let state = Rc::new(RefCell::new(SleepState::default()));
+    let timer_state = state.clone();
+    ic_cdk_timers::set_timer(delay, move || {
+        let waker = {
+            let mut state = timer_state.borrow_mut();
+            state.fired = true;
+            state.waker.take()
+        };
+        // The borrow is released first: waking polls the task right away.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+    Sleep { state }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.borrow_mut();
+        if state.fired {
+            return Poll::Ready(());
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
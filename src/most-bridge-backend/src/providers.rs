@@ -0,0 +1,142 @@
+use crate::constants::{
+    PROVIDER_FAILURE_PENALTY, PROVIDER_INITIAL_HEALTH, PROVIDER_MAX_HEALTH, PROVIDER_SUCCESS_REWARD,
+};
+use crate::events::{self, EventType};
+use crate::helper::{KeyName, Provider};
+use crate::{clock, PROVIDERS};
+use candid::CandidType;
+use ic_cdk::api::management_canister::http_request::HttpHeader;
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize)]
+pub struct ProviderArgs {
+    pub name: String,
+    pub url: String,
+    // None keeps the headers of an existing provider.
+    pub headers: Option<Vec<HttpHeader>>,
+}
+
+/// What queries return about a provider. Its headers may carry API keys, so
+/// only their number is exposed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ProviderStatus {
+    pub name: String,
+    pub url: String,
+    pub header_count: u64,
+    pub health: u32,
+    pub successes: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+    pub last_failure_at: Option<u64>,
+}
+
+pub fn is_http_url(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+/// Adds a provider, or updates the URL and headers of an existing one while
+/// keeping its health.
+pub fn set(args: ProviderArgs) -> Result<(), String> {
+    if args.name.is_empty() || !is_http_url(&args.url) {
+        return Err("A provider needs a name and an http(s) url".to_string());
+    }
+
+    // Headers may hold API keys, so they are left out of the public log.
+    events::record(EventType::SetProvider {
+        name: args.name.clone(),
+        url: args.url.clone(),
+    });
+    PROVIDERS.with(|providers| {
+        let mut providers = providers.borrow_mut();
+        let key = KeyName(args.name.clone());
+        let provider = match providers.get(&key) {
+            Some(mut provider) => {
+                provider.url = args.url;
+                if let Some(headers) = args.headers {
+                    provider.headers = headers;
+                }
+                provider
+            }
+            None => Provider {
+                name: args.name,
+                url: args.url,
+                headers: args.headers.unwrap_or_default(),
+                health: PROVIDER_INITIAL_HEALTH,
+                successes: 0,
+                failures: 0,
+                last_error: None,
+                last_failure_at: None,
+                added_at: clock::now(),
+            },
+        };
+        providers.insert(key, provider);
+    });
+    Ok(())
+}
+
+pub fn remove(name: String) -> Result<(), String> {
+    PROVIDERS
+        .with(|providers| providers.borrow_mut().remove(&KeyName(name.clone())))
+        .ok_or(format!("Unknown provider {name}"))?;
+    events::record(EventType::RemovedProvider { name });
+    Ok(())
+}
+
+pub fn is_empty() -> bool {
+    PROVIDERS.with(|providers| providers.borrow().is_empty())
+}
+
+pub fn list() -> Vec<ProviderStatus> {
+    PROVIDERS.with(|providers| {
+        providers
+            .borrow()
+            .iter()
+            .map(|(_, provider)| ProviderStatus {
+                name: provider.name,
+                url: provider.url,
+                header_count: provider.headers.len() as u64,
+                health: provider.health,
+                successes: provider.successes,
+                failures: provider.failures,
+                last_error: provider.last_error,
+                last_failure_at: provider.last_failure_at,
+            })
+            .collect()
+    })
+}
+
+/// Providers in the order they should be tried: healthiest first, ties
+/// broken by name so every replica agrees.
+pub fn ordered() -> Vec<Provider> {
+    let mut providers: Vec<Provider> =
+        PROVIDERS.with(|providers| providers.borrow().iter().map(|(_, p)| p).collect());
+    providers.sort_by(|a, b| b.health.cmp(&a.health).then_with(|| a.name.cmp(&b.name)));
+    providers
+}
+
+fn update(name: &str, f: impl FnOnce(&mut Provider)) {
+    PROVIDERS.with(|providers| {
+        let mut providers = providers.borrow_mut();
+        let key = KeyName(name.to_string());
+        if let Some(mut provider) = providers.get(&key) {
+            f(&mut provider);
+            providers.insert(key, provider);
+        }
+    });
+}
+
+pub fn record_success(name: &str) {
+    update(name, |provider| {
+        provider.successes += 1;
+        provider.health = (provider.health + PROVIDER_SUCCESS_REWARD).min(PROVIDER_MAX_HEALTH);
+    });
+}
+
+pub fn record_failure(name: &str, error: &str) {
+    update(name, |provider| {
+        provider.failures += 1;
+        provider.health = provider.health.saturating_sub(PROVIDER_FAILURE_PENALTY);
+        provider.last_error = Some(error.to_string());
+        provider.last_failure_at = Some(clock::now());
+    });
+}
@@ -0,0 +1,217 @@
+use crate::common::SignatureScheme;
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use std::fmt;
+
+const SECP256K1_SIGNATURE_LENGTH: usize = 64;
+const SECP256K1_PUBLIC_KEY_LENGTH: usize = 33;
+const ED25519_SIGNATURE_LENGTH: usize = 64;
+const ED25519_PUBLIC_KEY_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    InvalidSignatureLength { expected: usize, actual: usize },
+    InvalidPublicKeyLength { expected: usize, actual: usize },
+    MalformedSignature(String),
+    MalformedPublicKey(String),
+    VerificationFailed,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSignatureLength { expected, actual } => write!(
+                f,
+                "invalid signature length: expected {expected} bytes, got {actual}"
+            ),
+            Self::InvalidPublicKeyLength { expected, actual } => write!(
+                f,
+                "invalid public key length: expected {expected} bytes, got {actual}"
+            ),
+            Self::MalformedSignature(e) => write!(f, "malformed signature: {e}"),
+            Self::MalformedPublicKey(e) => write!(f, "malformed public key: {e}"),
+            Self::VerificationFailed => {
+                write!(f, "signature does not verify against the public key")
+            }
+        }
+    }
+}
+
+fn check_signature_length(signature: &[u8], expected: usize) -> Result<(), SignatureError> {
+    if signature.len() != expected {
+        return Err(SignatureError::InvalidSignatureLength {
+            expected,
+            actual: signature.len(),
+        });
+    }
+    Ok(())
+}
+
+fn check_public_key_length(public_key: &[u8], expected: usize) -> Result<(), SignatureError> {
+    if public_key.len() != expected {
+        return Err(SignatureError::InvalidPublicKeyLength {
+            expected,
+            actual: public_key.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks a signature returned by the management canister and returns the
+/// bytes Sui accepts for `scheme`.
+///
+/// secp256k1 signatures are normalized to low-S, which Sui requires, and
+/// verified against `public_key` over sha256 of the intent digest.
+pub fn normalize(
+    scheme: SignatureScheme,
+    digest: [u8; 32],
+    public_key: &[u8],
+    signature: &[u8],
+) -> Result<Vec<u8>, SignatureError> {
+    match scheme {
+        SignatureScheme::Secp256k1 => normalize_secp256k1(digest, public_key, signature),
+        SignatureScheme::Ed25519 => {
+            check_signature_length(signature, ED25519_SIGNATURE_LENGTH)?;
+            check_public_key_length(public_key, ED25519_PUBLIC_KEY_LENGTH)?;
+            Ok(signature.to_vec())
+        }
+    }
+}
+
+fn normalize_secp256k1(
+    digest: [u8; 32],
+    public_key: &[u8],
+    signature: &[u8],
+) -> Result<Vec<u8>, SignatureError> {
+    check_signature_length(signature, SECP256K1_SIGNATURE_LENGTH)?;
+    check_public_key_length(public_key, SECP256K1_PUBLIC_KEY_LENGTH)?;
+
+    let signature = Signature::from_slice(signature)
+        .map_err(|e| SignatureError::MalformedSignature(e.to_string()))?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let verifying_key = VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|e| SignatureError::MalformedPublicKey(e.to_string()))?;
+
+    verifying_key
+        .verify_prehash(&crate::sha256(digest), &signature)
+        .map_err(|_| SignatureError::VerificationFailed)?;
+    Ok(signature.to_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+    use k256::ecdsa::SigningKey;
+
+    const DIGEST: [u8; 32] = [7; 32];
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_slice(&[1; 32]).unwrap()
+    }
+
+    fn public_key(key: &SigningKey) -> Vec<u8> {
+        key.verifying_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn sign(key: &SigningKey) -> Signature {
+        key.sign_prehash(&crate::sha256(DIGEST)).unwrap()
+    }
+
+    #[test]
+    fn normalizes_high_s_signatures() {
+        let key = signing_key();
+        let low = sign(&key);
+        let high = Signature::from_scalars(low.r().to_bytes(), (-*low.s()).to_bytes()).unwrap();
+        assert!(high.normalize_s().is_some());
+
+        let expected = low.to_bytes().to_vec();
+        for signature in [low, high] {
+            let normalized = normalize(
+                SignatureScheme::Secp256k1,
+                DIGEST,
+                &public_key(&key),
+                &signature.to_bytes(),
+            );
+            assert_eq!(normalized, Ok(expected.clone()));
+        }
+    }
+
+    #[test]
+    fn rejects_signatures_by_another_key() {
+        let other = SigningKey::from_slice(&[2; 32]).unwrap();
+        let signature = sign(&other).to_bytes();
+        assert_eq!(
+            normalize(
+                SignatureScheme::Secp256k1,
+                DIGEST,
+                &public_key(&signing_key()),
+                &signature,
+            ),
+            Err(SignatureError::VerificationFailed)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let key = signing_key();
+        let signature = sign(&key).to_bytes();
+        assert_eq!(
+            normalize(
+                SignatureScheme::Secp256k1,
+                DIGEST,
+                &public_key(&key),
+                &signature[..63]
+            ),
+            Err(SignatureError::InvalidSignatureLength {
+                expected: 64,
+                actual: 63
+            })
+        );
+        assert_eq!(
+            normalize(SignatureScheme::Secp256k1, DIGEST, &[2; 32], &signature),
+            Err(SignatureError::InvalidPublicKeyLength {
+                expected: 33,
+                actual: 32
+            })
+        );
+        assert!(matches!(
+            normalize(SignatureScheme::Secp256k1, DIGEST, &[9; 33], &signature),
+            Err(SignatureError::MalformedPublicKey(_))
+        ));
+        assert!(matches!(
+            normalize(
+                SignatureScheme::Secp256k1,
+                DIGEST,
+                &public_key(&key),
+                &[0; 64]
+            ),
+            Err(SignatureError::MalformedSignature(_))
+        ));
+    }
+
+    #[test]
+    fn passes_ed25519_signatures_through() {
+        assert_eq!(
+            normalize(SignatureScheme::Ed25519, DIGEST, &[1; 32], &[2; 64]),
+            Ok(vec![2; 64])
+        );
+        assert_eq!(
+            normalize(SignatureScheme::Ed25519, DIGEST, &[1; 32], &[2; 65]),
+            Err(SignatureError::InvalidSignatureLength {
+                expected: 64,
+                actual: 65
+            })
+        );
+        assert_eq!(
+            normalize(SignatureScheme::Ed25519, DIGEST, &[1; 33], &[2; 64]),
+            Err(SignatureError::InvalidPublicKeyLength {
+                expected: 32,
+                actual: 33
+            })
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use crate::common::SignatureScheme;
+use crate::constants::{MAX_INPUT_COINS, SUI_COIN_TYPE, SUI_TX_INTENT};
+use crate::logs::INFO;
+use crate::models::{
+    CoinPage, ExecuteTxBlockResponseResult, NextCursor, PaySuiResponseResult, ReceiptResult,
+    ResponseSizeEstimate, SuiObjectResponse, SuiTransactionBlockPage,
+};
+use crate::outcall::{self, OutcallError};
+use crate::providers;
+use crate::transforms::{
+    TRANSFORM_COINS, TRANSFORM_EVENTS, TRANSFORM_OBJECT, TRANSFORM_TX_BUILD, TRANSFORM_TX_EXECUTE,
+    TRANSFORM_TX_LOOKUP, TRANSFORM_TX_QUERY,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use ic_canister_log::log;
+use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+type Blake2b256 = Blake2b<U32>;
+
+/// Calls a Sui full node JSON-RPC method, trying the registered providers
+/// from the healthiest down until one of them answers. Each provider gets
+/// its transient failures retried before the next one is tried. The
+/// response goes through `transform`, which must keep the fields of `T`.
+pub async fn call<T: DeserializeOwned>(
+    method: &str,
+    params: Value,
+    response_size_estimate: ResponseSizeEstimate,
+    transform: &str,
+) -> Result<T, OutcallError> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    });
+
+    let mut last_error = OutcallError::NoProvider;
+    for provider in providers::ordered() {
+        let mut headers = vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }];
+        headers.extend(provider.headers.iter().cloned());
+        let request = CanisterHttpRequestArgument {
+            url: provider.url.clone(),
+            max_response_bytes: None,
+            method: HttpMethod::POST,
+            headers,
+            body: Some(body.to_string().into_bytes()),
+            transform: Some(TransformContext::from_name(transform.to_owned(), vec![])),
+        };
+
+        match outcall::http_request_with_retry(method, request, response_size_estimate).await {
+            Ok(response) => {
+                // A JSON-RPC error is the provider answering, so it counts as
+                // a success and isn't worth asking another provider about.
+                providers::record_success(&provider.name);
+                return outcall::decode_json_rpc(method, &response.body);
+            }
+            Err(error) => {
+                log!(
+                    INFO,
+                    "The {method} request to provider {} failed: {error}",
+                    provider.name
+                );
+                providers::record_failure(&provider.name, &error.to_string());
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Executes a signed transaction and waits for the full node to apply its effects.
+pub async fn execute_transaction(
+    tx_bytes: String,
+    signature: String,
+) -> Result<ExecuteTxBlockResponseResult, OutcallError> {
+    let result: ExecuteTxBlockResponseResult = call(
+        "sui_executeTransactionBlock",
+        json!([
+            tx_bytes,
+            [signature],
+            { "showEffects": true },
+            "WaitForLocalExecution"
+        ]),
+        ResponseSizeEstimate::new(4 * 1024),
+        TRANSFORM_TX_EXECUTE,
+    )
+    .await?;
+    if result.effects.is_none() {
+        return Err(OutcallError::Parse(format!(
+            "Missing effects for transaction {}",
+            result.digest
+        )));
+    }
+    Ok(result)
+}
+
+/// Returns one page of the SUI coins owned by `owner`.
+pub async fn get_coins(owner: &str, cursor: Option<String>) -> Result<CoinPage, OutcallError> {
+    call(
+        "suix_getCoins",
+        json!([owner, SUI_COIN_TYPE, cursor, MAX_INPUT_COINS]),
+        ResponseSizeEstimate::new(MAX_INPUT_COINS * 300),
+        TRANSFORM_COINS,
+    )
+    .await
+}
+
+/// Builds a programmable transaction that merges `input_coins` into the gas coin,
+/// splits one coin per recipient and transfers it.
+pub async fn pay_sui(
+    signer: &str,
+    input_coins: Vec<String>,
+    recipients: Vec<String>,
+    amounts: Vec<u64>,
+    gas_budget: u64,
+) -> Result<PaySuiResponseResult, OutcallError> {
+    let amounts: Vec<String> = amounts.iter().map(|a| a.to_string()).collect();
+    call(
+        "unsafe_paySui",
+        json!([
+            signer,
+            input_coins,
+            recipients,
+            amounts,
+            gas_budget.to_string()
+        ]),
+        ResponseSizeEstimate::new(16 * 1024),
+        TRANSFORM_TX_BUILD,
+    )
+    .await
+}
+
+/// Builds a programmable transaction that splits one coin per recipient off the
+/// merged `input_coins` and pays for gas with the separate `gas` coin.
+pub async fn pay(
+    signer: &str,
+    input_coins: Vec<String>,
+    recipients: Vec<String>,
+    amounts: Vec<u64>,
+    gas: String,
+    gas_budget: u64,
+) -> Result<PaySuiResponseResult, OutcallError> {
+    let amounts: Vec<String> = amounts.iter().map(|a| a.to_string()).collect();
+    call(
+        "unsafe_pay",
+        json!([
+            signer,
+            input_coins,
+            recipients,
+            amounts,
+            gas,
+            gas_budget.to_string()
+        ]),
+        ResponseSizeEstimate::new(16 * 1024),
+        TRANSFORM_TX_BUILD,
+    )
+    .await
+}
+
+/// Builds a transaction that sends everything in `input_coins` minus gas to
+/// `recipient`, paying for gas out of the merged coins.
+pub async fn pay_all_sui(
+    signer: &str,
+    input_coins: Vec<String>,
+    recipient: String,
+    gas_budget: u64,
+) -> Result<PaySuiResponseResult, OutcallError> {
+    call(
+        "unsafe_payAllSui",
+        json!([signer, input_coins, recipient, gas_budget.to_string()]),
+        ResponseSizeEstimate::new(16 * 1024),
+        TRANSFORM_TX_BUILD,
+    )
+    .await
+}
+
+/// Builds a `MergeCoins` transaction that merges `coin_to_merge` into `primary_coin`.
+pub async fn merge_coins(
+    signer: &str,
+    primary_coin: String,
+    coin_to_merge: String,
+    gas: String,
+    gas_budget: u64,
+) -> Result<PaySuiResponseResult, OutcallError> {
+    call(
+        "unsafe_mergeCoins",
+        json!([
+            signer,
+            primary_coin,
+            coin_to_merge,
+            gas,
+            gas_budget.to_string()
+        ]),
+        ResponseSizeEstimate::new(4 * 1024),
+        TRANSFORM_TX_BUILD,
+    )
+    .await
+}
+
+/// Looks up an executed transaction with its effects and checkpoint.
+pub async fn get_transaction(
+    tx_digest: &str,
+) -> Result<ExecuteTxBlockResponseResult, OutcallError> {
+    call(
+        "sui_getTransactionBlock",
+        json!([tx_digest, { "showEffects": true }]),
+        ResponseSizeEstimate::new(4 * 1024),
+        TRANSFORM_TX_LOOKUP,
+    )
+    .await
+}
+
+/// Looks up the current version of an object, or why it has none.
+pub async fn get_object(object_id: &str) -> Result<SuiObjectResponse, OutcallError> {
+    call(
+        "sui_getObject",
+        json!([object_id, {}]),
+        ResponseSizeEstimate::new(1024),
+        TRANSFORM_OBJECT,
+    )
+    .await
+}
+
+/// Returns the transactions sent to `address` after `cursor`, oldest first,
+/// with their balance changes.
+pub async fn query_transactions_to(
+    address: &str,
+    cursor: Option<String>,
+    limit: u64,
+) -> Result<SuiTransactionBlockPage, OutcallError> {
+    call(
+        "suix_queryTransactionBlocks",
+        json!([
+            {
+                "filter": { "ToAddress": address },
+                "options": { "showBalanceChanges": true }
+            },
+            cursor,
+            limit,
+            false
+        ]),
+        ResponseSizeEstimate::new(limit * 1024),
+        TRANSFORM_TX_QUERY,
+    )
+    .await
+}
+
+/// Returns the Move events of type `event_type` emitted after `cursor`,
+/// oldest first.
+pub async fn query_events(
+    event_type: &str,
+    cursor: Option<NextCursor>,
+    limit: u64,
+) -> Result<ReceiptResult, OutcallError> {
+    call(
+        "suix_queryEvents",
+        json!([{ "MoveEventType": event_type }, cursor, limit, false]),
+        ResponseSizeEstimate::new(limit * 1024),
+        TRANSFORM_EVENTS,
+    )
+    .await
+}
+
+/// Whether `event_type` is a Move struct type, `<package>::<module>::<name>`.
+pub fn is_move_event_type(event_type: &str) -> bool {
+    let parts: Vec<&str> = event_type.split("::").collect();
+    let is_identifier = |part: &str| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    parts.len() == 3
+        && canonical_address(parts[0]).is_some()
+        && is_identifier(parts[1])
+        && is_identifier(parts[2])
+}
+
+/// Computes the digest the minter signs for the base64 encoded `tx_bytes`.
+pub fn intent_digest(tx_bytes: &str) -> Result<[u8; 32], String> {
+    let tx_bytes = STANDARD
+        .decode(tx_bytes)
+        .map_err(|e| format!("Invalid tx bytes: {}", e))?;
+
+    let mut hasher = Blake2b256::new();
+    hasher.update(SUI_TX_INTENT);
+    hasher.update(&tx_bytes);
+    Ok(hasher.finalize().into())
+}
+
+/// Computes the base58 transaction digest Sui assigns to the base64 encoded `tx_bytes`.
+pub fn transaction_digest(tx_bytes: &str) -> Result<String, String> {
+    let tx_bytes = STANDARD
+        .decode(tx_bytes)
+        .map_err(|e| format!("Invalid tx bytes: {}", e))?;
+
+    let mut hasher = Blake2b256::new();
+    hasher.update(b"TransactionData::");
+    hasher.update(&tx_bytes);
+    Ok(bs58::encode(hasher.finalize()).into_string())
+}
+
+/// Derives the Sui address of `public_key`: blake2b-256 of the scheme flag
+/// followed by the public key bytes. secp256k1 keys must be SEC1 compressed.
+pub fn address_from_public_key(
+    scheme: SignatureScheme,
+    public_key: &[u8],
+) -> Result<String, String> {
+    let expected = match scheme {
+        SignatureScheme::Ed25519 => 32,
+        SignatureScheme::Secp256k1 => 33,
+    };
+    if public_key.len() != expected {
+        return Err(format!(
+            "Invalid {:?} public key length: expected {expected} bytes, got {}",
+            scheme,
+            public_key.len()
+        ));
+    }
+
+    let mut hasher = Blake2b256::new();
+    hasher.update([scheme.flag()]);
+    hasher.update(public_key);
+    Ok(format!("0x{}", hex::encode(hasher.finalize())))
+}
+
+/// Compares two Sui addresses, ignoring the `0x` prefix, case and leading zeros.
+pub fn same_address(a: &str, b: &str) -> bool {
+    let normalize = |address: &str| {
+        let address = address.trim().to_lowercase();
+        let address = address.strip_prefix("0x").unwrap_or(&address).to_string();
+        address.trim_start_matches('0').to_string()
+    };
+    normalize(a) == normalize(b)
+}
+
+/// The `0x` prefixed, lowercase, 64 hex digit form of a Sui address, or None
+/// if `address` isn't one.
+pub fn canonical_address(address: &str) -> Option<String> {
+    let address = address.trim().to_lowercase();
+    let hex = address.strip_prefix("0x").unwrap_or(&address);
+    if hex.is_empty() || hex.len() > 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("0x{:0>64}", hex))
+}
+
+/// Whether `digest` is a base58 encoded 32 byte transaction digest.
+pub fn is_transaction_digest(digest: &str) -> bool {
+    bs58::decode(digest)
+        .into_vec()
+        .map_or(false, |bytes| bytes.len() == 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_ed25519_address() {
+        // RFC 8032 test 1 public key.
+        let public_key =
+            hex::decode("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+                .unwrap();
+        assert_eq!(
+            address_from_public_key(SignatureScheme::Ed25519, &public_key).unwrap(),
+            "0x304af458e90e97c841685b8cbbc59b909f3e2cf150df590ada4c81452c29737d"
+        );
+    }
+
+    #[test]
+    fn derives_secp256k1_address() {
+        // The compressed generator point, the public key of secret key 1.
+        let public_key =
+            hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        assert_eq!(
+            address_from_public_key(SignatureScheme::Secp256k1, &public_key).unwrap(),
+            "0xd4c3524e6642b2e54945c02378024f822ac3f80b0870a5f95f06e68a61890a6c"
+        );
+    }
+
+    #[test]
+    fn rejects_public_keys_of_the_wrong_length() {
+        assert!(address_from_public_key(SignatureScheme::Ed25519, &[0; 33]).is_err());
+        assert!(address_from_public_key(SignatureScheme::Secp256k1, &[2; 32]).is_err());
+    }
+
+    #[test]
+    fn compares_addresses() {
+        assert!(same_address("0x0000ab", "AB"));
+        assert!(same_address(" 0xAb ", "0x00ab"));
+        assert!(!same_address("0xab", "0xabc"));
+    }
+
+    #[test]
+    fn canonicalizes_addresses() {
+        assert_eq!(
+            canonical_address("0xAB").unwrap(),
+            format!("0x{}ab", "0".repeat(62))
+        );
+        assert_eq!(
+            canonical_address(&"f".repeat(64)).unwrap(),
+            format!("0x{}", "f".repeat(64))
+        );
+        assert_eq!(canonical_address(""), None);
+        assert_eq!(canonical_address("0x"), None);
+        assert_eq!(canonical_address("0xzz"), None);
+        assert_eq!(canonical_address(&"1".repeat(65)), None);
+    }
+
+    #[test]
+    fn checks_event_types() {
+        assert!(is_move_event_type("0x2a::ckSuiHelper::ReceivedSui"));
+        assert!(!is_move_event_type("0x2a::ckSuiHelper"));
+        assert!(!is_move_event_type("0x2a::ckSuiHelper::ReceivedSui::x"));
+        assert!(!is_move_event_type("most::ckSuiHelper::ReceivedSui"));
+        assert!(!is_move_event_type("0x2a::::ReceivedSui"));
+        assert!(!is_move_event_type("https://events.example"));
+    }
+
+    #[test]
+    fn computes_digests() {
+        // The bytes 0..16.
+        let tx_bytes = "AAECAwQFBgcICQoLDA0ODw==";
+        let digest = transaction_digest(tx_bytes).unwrap();
+        assert_eq!(digest, "9Hv7xkPNnfsjQM8JsojdE2PSHTWQgSvhRHf5ZeTaZ7Pq");
+        assert!(is_transaction_digest(&digest));
+        assert_eq!(
+            hex::encode(intent_digest(tx_bytes).unwrap()),
+            "f189f0e15298704cb8bdcd14c2f98a150d1ad438e1acc0faeafae55829111a79"
+        );
+        assert!(transaction_digest("not base64!").is_err());
+        assert!(!is_transaction_digest("0x1"));
+    }
+}
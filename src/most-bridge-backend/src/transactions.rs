@@ -0,0 +1,282 @@
+use crate::common::KeyConfig;
+use crate::constants::SIGNED_TX_EXPIRY_NANOS;
+use crate::events::{self, EventType};
+use crate::helper::{KeyName, KeyValue, ObjectVersion, SignedTransaction};
+use crate::logs::INFO;
+use crate::models::{ExecuteTxBlockResponseResult, PaySuiResponseResult};
+use crate::outcall::OutcallError;
+use crate::{clock, sui, RESERVED_OBJECTS, SIGNED_TRANSACTIONS};
+use ic_canister_log::log;
+use std::collections::BTreeSet;
+
+fn reservation_key(object_id: &str, version: u64) -> KeyName {
+    KeyName(format!("{}:{}", object_id, version))
+}
+
+/// Returns true if `version` of the object is used by a signed transaction
+/// whose outcome is not known yet.
+pub fn is_reserved(object_id: &str, version: u64) -> bool {
+    RESERVED_OBJECTS.with(|reserved| {
+        reserved
+            .borrow()
+            .contains_key(&reservation_key(object_id, version))
+    })
+}
+
+/// Block indices of the withdrawals paid out by outstanding signed transactions.
+pub fn in_flight_withdrawals() -> BTreeSet<u64> {
+    SIGNED_TRANSACTIONS.with(|signed| {
+        signed
+            .borrow()
+            .iter()
+            .flat_map(|(_, tx)| tx.block_indices)
+            .collect()
+    })
+}
+
+pub fn has_outstanding() -> bool {
+    SIGNED_TRANSACTIONS.with(|signed| !signed.borrow().is_empty())
+}
+
+fn objects_of(tx: &PaySuiResponseResult) -> Vec<ObjectVersion> {
+    let gas = tx.gas.iter().map(|gas| ObjectVersion {
+        object_id: gas.object_id.clone(),
+        version: gas.version as u64,
+    });
+    let inputs = tx.input_objects.iter().map(|input| ObjectVersion {
+        object_id: input.imm_or_owned_move_object.object_id.clone(),
+        version: input.imm_or_owned_move_object.version as u64,
+    });
+    gas.chain(inputs).collect()
+}
+
+/// Reserves the object versions for the transaction `key`, failing if any of
+/// them is already reserved by a different transaction.
+fn reserve(key: &str, objects: &[ObjectVersion]) -> Result<(), String> {
+    RESERVED_OBJECTS.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        for object in objects {
+            if let Some(owner) = reserved.get(&reservation_key(&object.object_id, object.version)) {
+                if owner.0 != key {
+                    return Err(format!(
+                        "Object {} version {} is reserved by transaction {}",
+                        object.object_id, object.version, owner.0
+                    ));
+                }
+            }
+        }
+        for object in objects {
+            reserved.insert(
+                reservation_key(&object.object_id, object.version),
+                KeyValue(key.to_string()),
+            );
+        }
+        Ok(())
+    })
+}
+
+fn release(key: &str) {
+    let signed =
+        SIGNED_TRANSACTIONS.with(|signed| signed.borrow_mut().remove(&KeyName(key.to_string())));
+    if let Some(signed) = signed {
+        RESERVED_OBJECTS.with(|reserved| {
+            let mut reserved = reserved.borrow_mut();
+            for object in &signed.objects {
+                reserved.remove(&reservation_key(&object.object_id, object.version));
+            }
+        });
+    }
+}
+
+/// Reserves the objects of `tx`, signs it with the minter key and executes it.
+/// Returns the effects once Sui executed the transaction, successfully or not.
+pub async fn sign_and_execute(
+    task: &str,
+    tx: PaySuiResponseResult,
+    block_indices: Vec<u64>,
+) -> Result<ExecuteTxBlockResponseResult, String> {
+    sign_and_execute_with_path(task, tx, block_indices, crate::key_config(), vec![]).await
+}
+
+/// Like `sign_and_execute`, for a transaction sent from the address `signing_key`
+/// controls under `derivation_path`.
+pub async fn sign_and_execute_with_path(
+    task: &str,
+    tx: PaySuiResponseResult,
+    block_indices: Vec<u64>,
+    signing_key: KeyConfig,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<ExecuteTxBlockResponseResult, String> {
+    let digest = sui::intent_digest(&tx.tx_bytes)?;
+    let key = hex::encode(digest);
+    let objects = objects_of(&tx);
+    reserve(&key, &objects)?;
+
+    // The transaction is stored before signing, so its objects stay reserved
+    // even if this call never gets to record the signature.
+    SIGNED_TRANSACTIONS.with(|signed| {
+        signed.borrow_mut().insert(
+            KeyName(key.clone()),
+            SignedTransaction {
+                task: task.to_string(),
+                tx_bytes: tx.tx_bytes,
+                signature: None,
+                objects,
+                block_indices: block_indices.clone(),
+                created_at: clock::now(),
+            },
+        )
+    });
+
+    let public_key = if derivation_path.is_empty() && signing_key == crate::key_config() {
+        crate::get_public_key().await.and_then(|response| {
+            crate::validate_sui_address(&response.public_key)?;
+            Ok(response.public_key)
+        })
+    } else {
+        crate::derive_public_key(signing_key, derivation_path.clone())
+            .await
+            .map(|response| response.public_key)
+    };
+    let public_key = match public_key {
+        Ok(public_key) => public_key,
+        Err(e) => {
+            release(&key);
+            return Err(e);
+        }
+    };
+    let signature =
+        match crate::encode_signature(signing_key, digest, public_key, derivation_path).await {
+            Ok(signature) => signature,
+            Err(e) => {
+                release(&key);
+                return Err(format!("Failed to sign transaction {key}: {e}"));
+            }
+        };
+
+    SIGNED_TRANSACTIONS.with(|signed| {
+        let mut signed = signed.borrow_mut();
+        if let Some(mut tx) = signed.get(&KeyName(key.clone())) {
+            tx.signature = Some(signature);
+            signed.insert(KeyName(key.clone()), tx);
+        }
+    });
+    events::record(EventType::SignedTransaction {
+        key: key.clone(),
+        task: task.to_string(),
+        block_indices,
+    });
+
+    submit(&key).await
+}
+
+/// Submits the stored transaction with its original signature and releases its
+/// objects once it has been executed. A failed execution still consumes the
+/// object versions, so they are released as well.
+async fn submit(key: &str) -> Result<ExecuteTxBlockResponseResult, String> {
+    let signed = SIGNED_TRANSACTIONS
+        .with(|signed| signed.borrow().get(&KeyName(key.to_string())))
+        .ok_or(format!("Unknown transaction {}", key))?;
+    let signature = signed
+        .signature
+        .ok_or(format!("Transaction {} was never signed", key))?;
+
+    let executed = sui::execute_transaction(signed.tx_bytes, signature)
+        .await
+        .map_err(|e| format!("Failed to execute transaction {}: {}", key, e))?;
+    events::record(EventType::SubmittedTransaction {
+        key: key.to_string(),
+        tx_digest: executed.digest.clone(),
+    });
+    release(key);
+    Ok(executed)
+}
+
+/// Re-submits every transaction `task` signed earlier whose outcome is still unknown.
+pub async fn retry_outstanding(
+    task: &str,
+) -> Vec<(
+    SignedTransaction,
+    Result<ExecuteTxBlockResponseResult, String>,
+)> {
+    let outstanding: Vec<(String, SignedTransaction)> = SIGNED_TRANSACTIONS.with(|signed| {
+        signed
+            .borrow()
+            .iter()
+            .filter(|(_, tx)| tx.task == task)
+            .map(|(key, tx)| (key.0, tx))
+            .collect()
+    });
+
+    let mut results = vec![];
+    for (key, tx) in outstanding {
+        // A transaction without a signature was never submitted, so its objects
+        // can't be locked on Sui. It is reported so that its withdrawals are
+        // queued again.
+        if tx.signature.is_none() {
+            release(&key);
+            results.push((tx, Err(format!("Transaction {key} was never signed"))));
+            continue;
+        }
+
+        let result = match submit(&key).await {
+            Err(e) if clock::now().saturating_sub(tx.created_at) > SIGNED_TX_EXPIRY_NANOS => {
+                match settle_expired(&key, &tx).await {
+                    Ok(Some(executed)) => Ok(executed),
+                    Ok(None) => {
+                        log!(INFO, "Abandoning transaction {key} after it expired: {e}");
+                        Err(e)
+                    }
+                    Err(check) => {
+                        log!(INFO, "Keeping expired transaction {key}: {check}");
+                        Err(e)
+                    }
+                }
+            }
+            result => result,
+        };
+        results.push((tx, result));
+    }
+    results
+}
+
+/// Releases an expired transaction once it is known to have executed, or
+/// known never to execute: one of its objects moved past the reserved
+/// version while Sui has no transaction with its digest. Until then its
+/// withdrawals stay in flight, since the signed bytes could still pay them.
+async fn settle_expired(
+    key: &str,
+    tx: &SignedTransaction,
+) -> Result<Option<ExecuteTxBlockResponseResult>, String> {
+    let mut consumed = false;
+    for object in &tx.objects {
+        if sui::get_object(&object.object_id)
+            .await?
+            .is_past(object.version)
+        {
+            consumed = true;
+            break;
+        }
+    }
+    if !consumed {
+        return Err("its objects are still at the reserved versions".to_string());
+    }
+
+    let digest = sui::transaction_digest(&tx.tx_bytes)?;
+    match sui::get_transaction(&digest).await {
+        Ok(executed) => {
+            events::record(EventType::SubmittedTransaction {
+                key: key.to_string(),
+                tx_digest: executed.digest.clone(),
+            });
+            release(key);
+            Ok(Some(executed))
+        }
+        // The full node answering that it doesn't know the digest.
+        Err(OutcallError::JsonRpc { .. }) => {
+            release(key);
+            Ok(None)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -0,0 +1,109 @@
+use crate::models::{
+    CoinPage, ExecuteTxBlockResponseResult, JsonRpcError, JsonRpcResponse, PaySuiResponseResult,
+    ReceiptResult, SuiObjectResponse, SuiTransactionBlockPage,
+};
+use candid::Nat;
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use ic_cdk::query;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub const TRANSFORM_EVENTS: &str = "transform_events";
+pub const TRANSFORM_TX_BUILD: &str = "transform_tx_build";
+pub const TRANSFORM_TX_EXECUTE: &str = "transform_tx_execute";
+pub const TRANSFORM_TX_LOOKUP: &str = "transform_tx_lookup";
+pub const TRANSFORM_COINS: &str = "transform_coins";
+pub const TRANSFORM_TX_QUERY: &str = "transform_tx_query";
+pub const TRANSFORM_OBJECT: &str = "transform_object";
+
+// JSON-RPC code used when a 2xx response doesn't have the expected shape.
+const INVALID_RESPONSE_CODE: i64 = -32700;
+
+fn error_response(status: Nat, code: i64, message: String) -> HttpResponse {
+    let body = JsonRpcResponse::<()> {
+        result: None,
+        error: Some(JsonRpcError { code, message }),
+    };
+    HttpResponse {
+        status,
+        headers: vec![],
+        body: serde_json::to_vec(&body).unwrap(),
+    }
+}
+
+/// Keeps only the `result` fields of `T`, or the code and message of the
+/// JSON-RPC `error`, and re-serializes them, so every replica ends up with
+/// the same bytes no matter which node answered or how it ordered its JSON.
+/// Headers are dropped, and non-2xx responses get a uniform error body that
+/// doesn't echo the provider's response.
+fn canonicalize<T: Serialize + DeserializeOwned>(raw: TransformArgs) -> HttpResponse {
+    canonicalize_with::<T>(raw, |_| {})
+}
+
+/// Like `canonicalize`, but first clears what `strip` removes from the result:
+/// fields that differ between nodes even when they agree on the outcome.
+fn canonicalize_with<T: Serialize + DeserializeOwned>(
+    raw: TransformArgs,
+    strip: impl FnOnce(&mut T),
+) -> HttpResponse {
+    let status = raw.response.status;
+    let code = crate::nat_to_u64(&status).unwrap_or(0);
+    if !(200..300).contains(&code) {
+        return error_response(status, code as i64, format!("HTTP status {}", code));
+    }
+
+    match serde_json::from_slice::<JsonRpcResponse<T>>(&raw.response.body) {
+        Ok(mut response) => {
+            if let Some(result) = response.result.as_mut() {
+                strip(result);
+            }
+            HttpResponse {
+                status,
+                headers: vec![],
+                body: serde_json::to_vec(&response).unwrap(),
+            }
+        }
+        Err(_) => error_response(
+            status,
+            INVALID_RESPONSE_CODE,
+            "Unexpected response body".to_string(),
+        ),
+    }
+}
+
+#[query]
+fn transform_events(raw: TransformArgs) -> HttpResponse {
+    canonicalize::<ReceiptResult>(raw)
+}
+
+#[query]
+fn transform_tx_build(raw: TransformArgs) -> HttpResponse {
+    canonicalize::<PaySuiResponseResult>(raw)
+}
+
+/// Each node waits for its own local execution, so only some of them already
+/// know the checkpoint. Finality is confirmed by a later lookup instead.
+#[query]
+fn transform_tx_execute(raw: TransformArgs) -> HttpResponse {
+    canonicalize_with::<ExecuteTxBlockResponseResult>(raw, |executed| executed.checkpoint = None)
+}
+
+#[query]
+fn transform_tx_lookup(raw: TransformArgs) -> HttpResponse {
+    canonicalize::<ExecuteTxBlockResponseResult>(raw)
+}
+
+#[query]
+fn transform_coins(raw: TransformArgs) -> HttpResponse {
+    canonicalize::<CoinPage>(raw)
+}
+
+#[query]
+fn transform_tx_query(raw: TransformArgs) -> HttpResponse {
+    canonicalize::<SuiTransactionBlockPage>(raw)
+}
+
+#[query]
+fn transform_object(raw: TransformArgs) -> HttpResponse {
+    canonicalize::<SuiObjectResponse>(raw)
+}
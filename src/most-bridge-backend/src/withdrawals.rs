@@ -0,0 +1,577 @@
+use crate::common::WithdrawalStatus;
+use crate::constants::{
+    LEDGER_CANISTER_ID_KEY, MAX_WITHDRAWAL_ATTEMPTS, MAX_WITHDRAWAL_BATCH_SIZE,
+    MINTER_SUI_ADDRESS_KEY, WITHDRAWALS_PAGE_SIZE, WITHDRAWALS_TASK, WITHDRAWAL_GAS_BUDGET,
+};
+use crate::events::{self, EventType};
+use crate::guard::TaskGuard;
+use crate::helper::{KeyName, PendingWithdrawal, WithdrawRecord, Withdrawal, WithdrawalState};
+use crate::logs::INFO;
+use crate::models::{ExecuteTxBlockResponseResult, PaySuiResponseResult};
+use crate::outcall::OutcallError;
+use crate::{
+    certification, clock, coins, history, sui, transactions, PENDING_WITHDRAWALS, WITHDRAWALS,
+    WITHDRAWAL_INDEX,
+};
+use candid::{Nat, Principal};
+use ic_canister_log::log;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+thread_local! {
+    // Halved every time a batch can't be built because of a withdrawal in it,
+    // so that a withdrawal that can't be paid out ends up alone in a batch and
+    // runs out of attempts instead of holding up the queue.
+    static BATCH_SIZE: Cell<u64> = Cell::new(MAX_WITHDRAWAL_BATCH_SIZE);
+}
+
+/// Queues a withdrawal and returns the number of queued withdrawals.
+pub fn enqueue(withdrawal: PendingWithdrawal) -> u64 {
+    events::record(EventType::AcceptedWithdrawal(withdrawal.clone()));
+    let queued = Withdrawal {
+        block_index: withdrawal.block_index,
+        from: withdrawal.from,
+        recipient: withdrawal.recipient.clone(),
+        amount: withdrawal.amount,
+        created_at: withdrawal.created_at,
+        state: WithdrawalState::Pending,
+        tx_digest: None,
+        error: None,
+        attempts: 0,
+        updated_at: withdrawal.created_at,
+        refund_created_at: None,
+        refund_unclear_since: None,
+    };
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow_mut()
+            .insert(withdrawal.block_index, queued.clone())
+    });
+    WITHDRAWAL_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert(index_key(&queued.from, queued.block_index), ())
+    });
+    certification::certify_withdrawal(&to_status(queued));
+
+    PENDING_WITHDRAWALS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        pending.insert(withdrawal.block_index, withdrawal);
+        pending.len()
+    })
+}
+
+/// Pays out the oldest queued withdrawals in a single Sui transaction.
+pub async fn flush() {
+    let _guard = match TaskGuard::new(WITHDRAWALS_TASK) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    for (signed, result) in transactions::retry_outstanding(WITHDRAWALS_TASK).await {
+        if let Err(e) = &result {
+            log!(
+                INFO,
+                "Signed batch of {} withdrawals is still outstanding: {e}",
+                signed.block_indices.len()
+            );
+        }
+        record_outcome(&signed.block_indices, result);
+    }
+
+    confirm_finality().await;
+    reimburse_failed().await;
+
+    let batch_size = BATCH_SIZE.with(|size| size.get());
+    let batch: Vec<PendingWithdrawal> = queued_in_state(WithdrawalState::Pending)
+        .into_iter()
+        .take(batch_size as usize)
+        .collect();
+
+    if batch.is_empty() {
+        return;
+    }
+
+    let block_indices: Vec<u64> = batch.iter().map(|w| w.block_index).collect();
+    let tx = match build_batch(&batch).await {
+        Ok(tx) => tx,
+        Err(BuildError::Withdrawal(e)) => {
+            log!(
+                INFO,
+                "Failed to build a batch of {} withdrawals: {e}",
+                batch.len()
+            );
+            record_build_failure(&block_indices, &e);
+            return;
+        }
+        // Not the fault of any withdrawal, so the queue is left as it is.
+        Err(BuildError::Unavailable(e)) => {
+            log!(
+                INFO,
+                "Can't build a batch of {} withdrawals yet: {e}",
+                batch.len()
+            );
+            return;
+        }
+    };
+    BATCH_SIZE.with(|size| size.set(MAX_WITHDRAWAL_BATCH_SIZE));
+
+    mark_submitted(&block_indices, &tx.digest);
+    let result =
+        transactions::sign_and_execute(WITHDRAWALS_TASK, tx.tx, block_indices.clone()).await;
+    if let Err(e) = &result {
+        log!(
+            INFO,
+            "Failed to flush a batch of {} withdrawals: {e}",
+            batch.len()
+        );
+    }
+    record_outcome(&block_indices, result);
+}
+
+/// A batch that can't be built is split until the withdrawal at fault is
+/// alone, which then uses up an attempt per failure.
+fn record_build_failure(block_indices: &[u64], error: &str) {
+    if block_indices.len() > 1 {
+        BATCH_SIZE.with(|size| size.set((block_indices.len() as u64 / 2).max(1)));
+        for block_index in block_indices {
+            update(*block_index, |withdrawal| {
+                withdrawal.error = Some(error.to_string())
+            });
+        }
+        return;
+    }
+    for block_index in block_indices {
+        update(*block_index, |withdrawal| {
+            withdrawal.attempts += 1;
+            if withdrawal.attempts >= MAX_WITHDRAWAL_ATTEMPTS {
+                withdrawal.state = WithdrawalState::Failed;
+            }
+            withdrawal.error = Some(error.to_string());
+        });
+    }
+}
+
+fn state_of(block_index: u64) -> Option<WithdrawalState> {
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .get(&block_index)
+            .map(|withdrawal| withdrawal.state)
+    })
+}
+
+/// Queued withdrawals in `state` that are not part of an outstanding signed transaction.
+fn queued_in_state(state: WithdrawalState) -> Vec<PendingWithdrawal> {
+    let in_flight = transactions::in_flight_withdrawals();
+    PENDING_WITHDRAWALS.with(|pending| {
+        pending
+            .borrow()
+            .iter()
+            .filter(|(block_index, _)| !in_flight.contains(block_index))
+            .filter(|(block_index, _)| state_of(*block_index) == Some(state))
+            .map(|(_, withdrawal)| withdrawal)
+            .collect()
+    })
+}
+
+fn update(block_index: u64, f: impl FnOnce(&mut Withdrawal)) {
+    WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals = withdrawals.borrow_mut();
+        if let Some(mut withdrawal) = withdrawals.get(&block_index) {
+            f(&mut withdrawal);
+            withdrawal.updated_at = clock::now();
+            withdrawals.insert(block_index, withdrawal.clone());
+            certification::certify_withdrawal(&to_status(withdrawal));
+        }
+    });
+}
+
+fn mark_submitted(block_indices: &[u64], tx_digest: &str) {
+    for block_index in block_indices {
+        update(*block_index, |withdrawal| {
+            withdrawal.state = WithdrawalState::Submitted;
+            withdrawal.tx_digest = Some(tx_digest.to_string());
+        });
+    }
+}
+
+/// Fans the result of a batch out to its withdrawals. Executed withdrawals stay
+/// `Submitted` until a checkpoint includes them, failed executions are retried
+/// and withdrawals whose transaction is no longer outstanding go back to the queue.
+fn record_outcome(block_indices: &[u64], result: Result<ExecuteTxBlockResponseResult, String>) {
+    match result {
+        Ok(executed) => match executed.failure() {
+            None if executed.checkpoint.is_some() => finalize(block_indices, &executed.digest),
+            None => mark_submitted(block_indices, &executed.digest),
+            Some(e) => record_failed_execution(block_indices, &executed.digest, &e),
+        },
+        Err(e) => {
+            let in_flight = transactions::in_flight_withdrawals();
+            for block_index in block_indices {
+                update(*block_index, |withdrawal| {
+                    if !in_flight.contains(block_index) {
+                        withdrawal.state = WithdrawalState::Pending;
+                        withdrawal.tx_digest = None;
+                    }
+                    withdrawal.error = Some(e.clone());
+                });
+            }
+        }
+    }
+}
+
+/// A transaction that executed with a failure status paid nothing out, so its
+/// withdrawals are queued again until they run out of attempts.
+fn record_failed_execution(block_indices: &[u64], tx_digest: &str, error: &str) {
+    log!(
+        INFO,
+        "Withdrawal batch of {} failed in ({tx_digest}): {error}",
+        block_indices.len()
+    );
+    events::record(EventType::FailedWithdrawalExecution {
+        block_indices: block_indices.to_vec(),
+        tx_digest: tx_digest.to_string(),
+        error: error.to_string(),
+    });
+    for block_index in block_indices {
+        update(*block_index, |withdrawal| {
+            withdrawal.attempts += 1;
+            withdrawal.state = if withdrawal.attempts >= MAX_WITHDRAWAL_ATTEMPTS {
+                WithdrawalState::Failed
+            } else {
+                WithdrawalState::Pending
+            };
+            withdrawal.tx_digest = Some(tx_digest.to_string());
+            withdrawal.error = Some(error.to_string());
+        });
+    }
+}
+
+/// Finalizes executed withdrawals once their transaction is part of a checkpoint.
+/// Only executed transactions reach this point, so a digest a provider doesn't
+/// know yet is looked up again on the next flush rather than queued again:
+/// unexecuted transactions are settled by `transactions::settle_expired`.
+async fn confirm_finality() {
+    let mut by_digest: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    for withdrawal in queued_in_state(WithdrawalState::Submitted) {
+        let tx_digest = WITHDRAWALS.with(|withdrawals| {
+            withdrawals
+                .borrow()
+                .get(&withdrawal.block_index)
+                .and_then(|withdrawal| withdrawal.tx_digest)
+        });
+        if let Some(tx_digest) = tx_digest {
+            by_digest
+                .entry(tx_digest)
+                .or_default()
+                .push(withdrawal.block_index);
+        }
+    }
+
+    for (tx_digest, block_indices) in by_digest {
+        match sui::get_transaction(&tx_digest).await {
+            Ok(executed) => match executed.failure() {
+                None if executed.checkpoint.is_some() => finalize(&block_indices, &tx_digest),
+                None => {}
+                Some(e) => record_failed_execution(&block_indices, &tx_digest, &e),
+            },
+            Err(e) => log!(INFO, "Failed to look up transaction {tx_digest}: {e}"),
+        }
+    }
+}
+
+/// Gives the ckSUI back to users whose withdrawals failed on every attempt.
+/// Each refund has a fixed `created_at_time` and a memo holding the block
+/// index, so a retry after an unclear outcome can't pay it twice.
+async fn reimburse_failed() {
+    use icrc_ledger_client::{CdkRuntime, ICRC1Client};
+    use icrc_ledger_types::icrc1::account::Account;
+    use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+    use serde_bytes::ByteBuf;
+
+    let failed = queued_in_state(WithdrawalState::Failed);
+    if failed.is_empty() {
+        return;
+    }
+
+    let ledger_canister_id = match crate::get(LEDGER_CANISTER_ID_KEY.to_string())
+        .and_then(|id| Principal::from_text(id).ok())
+    {
+        Some(id) => id,
+        None => return,
+    };
+    let client = ICRC1Client {
+        runtime: CdkRuntime,
+        ledger_canister_id,
+    };
+
+    for withdrawal in failed {
+        let created_at_time = refund_created_at(withdrawal.block_index);
+        let result = client
+            .transfer(TransferArg {
+                from_subaccount: None,
+                to: Account::from(withdrawal.from),
+                fee: None,
+                created_at_time: Some(created_at_time),
+                memo: Some(Memo(ByteBuf::from(
+                    withdrawal.block_index.to_be_bytes().to_vec(),
+                ))),
+                amount: Nat::from(withdrawal.amount),
+            })
+            .await;
+
+        match result {
+            Ok(Ok(refund_block_index))
+            | Ok(Err(TransferError::Duplicate {
+                duplicate_of: refund_block_index,
+            })) => record_reimbursed(&withdrawal, refund_block_index),
+            // The ledger no longer deduplicates against `created_at_time`. A new
+            // one is only safe if no attempt with the old one can have gone
+            // through.
+            Ok(Err(TransferError::TooOld)) => {
+                if refund_unclear_since(withdrawal.block_index).is_some() {
+                    log!(
+                        INFO,
+                        "Refund of withdrawal {} may have gone through, check the ledger",
+                        withdrawal.block_index
+                    );
+                } else {
+                    update(withdrawal.block_index, |w| w.refund_created_at = None);
+                }
+            }
+            // The ledger checks for duplicates before these, so no earlier
+            // transfer with this `created_at_time` went through.
+            Ok(Err(
+                err @ (TransferError::BadFee { .. }
+                | TransferError::BadBurn { .. }
+                | TransferError::InsufficientFunds { .. }),
+            )) => {
+                update(withdrawal.block_index, |w| w.refund_unclear_since = None);
+                log!(
+                    INFO,
+                    "Failed to reimburse withdrawal {}: {err}",
+                    withdrawal.block_index
+                );
+            }
+            Ok(Err(err)) => log!(
+                INFO,
+                "Failed to reimburse withdrawal {}: {err}",
+                withdrawal.block_index
+            ),
+            Err(err) => {
+                let now = clock::now();
+                update(withdrawal.block_index, |w| {
+                    w.refund_unclear_since.get_or_insert(now);
+                });
+                log!(
+                    INFO,
+                    "Failed to send a message to the ledger ({ledger_canister_id}): {err:?}"
+                );
+            }
+        }
+    }
+}
+
+fn refund_unclear_since(block_index: u64) -> Option<u64> {
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .get(&block_index)
+            .and_then(|withdrawal| withdrawal.refund_unclear_since)
+    })
+}
+
+/// The `created_at_time` of the refund of `block_index`, stored before the
+/// first transfer is attempted.
+fn refund_created_at(block_index: u64) -> u64 {
+    let stored = WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .get(&block_index)
+            .and_then(|withdrawal| withdrawal.refund_created_at)
+    });
+    stored.unwrap_or_else(|| {
+        let now = clock::now();
+        update(block_index, |withdrawal| {
+            withdrawal.refund_created_at = Some(now)
+        });
+        now
+    })
+}
+
+pub fn record_reimbursed(withdrawal: &PendingWithdrawal, refund_block_index: Nat) {
+    let state = WithdrawalState::Reimbursed {
+        refund_block_index: crate::nat_to_u64(&refund_block_index),
+    };
+    events::record(EventType::ReimbursedWithdrawal {
+        block_index: withdrawal.block_index,
+        refund_block_index: crate::nat_to_u64(&refund_block_index),
+    });
+    PENDING_WITHDRAWALS.with(|pending| pending.borrow_mut().remove(&withdrawal.block_index));
+    // The error of the last attempt is kept as the reason for the refund.
+    update(withdrawal.block_index, |w| w.state = state);
+    log!(
+        INFO,
+        "Reimbursed withdrawal {} in block {refund_block_index}",
+        withdrawal.block_index
+    );
+}
+
+pub fn finalize(block_indices: &[u64], tx_digest: &str) {
+    for block_index in block_indices {
+        let withdrawal =
+            PENDING_WITHDRAWALS.with(|pending| pending.borrow_mut().remove(block_index));
+        update(*block_index, |withdrawal| {
+            withdrawal.state = WithdrawalState::Finalized;
+            withdrawal.tx_digest = Some(tx_digest.to_string());
+            withdrawal.error = None;
+        });
+        if let Some(withdrawal) = withdrawal {
+            history::record_withdrawal(WithdrawRecord {
+                block_index: withdrawal.block_index,
+                amount: withdrawal.amount,
+                from: withdrawal.from,
+                recipient: Some(withdrawal.recipient),
+                tx_digest: tx_digest.to_string(),
+                created_at: clock::now(),
+            });
+        }
+    }
+    log!(
+        INFO,
+        "Withdrawal batch of {} finalized in ({tx_digest})",
+        block_indices.len()
+    );
+}
+
+/// Why a batch of withdrawals couldn't be built.
+enum BuildError {
+    /// Something about the withdrawals themselves: Sui rejects a recipient or
+    /// an amount, or the amounts overflow.
+    Withdrawal(String),
+    /// The minter can't build any batch right now, e.g. a provider is down or
+    /// it lacks gas or SUI.
+    Unavailable(String),
+}
+
+impl From<String> for BuildError {
+    fn from(error: String) -> Self {
+        BuildError::Unavailable(error)
+    }
+}
+
+impl From<&str> for BuildError {
+    fn from(error: &str) -> Self {
+        BuildError::Unavailable(error.to_string())
+    }
+}
+
+// JSON-RPC code of a request whose parameters the node rejects.
+const INVALID_PARAMS: i64 = -32602;
+
+/// An unsigned withdrawal transaction and its digest.
+struct BatchTransaction {
+    tx: PaySuiResponseResult,
+    digest: String,
+}
+
+async fn build_batch(batch: &[PendingWithdrawal]) -> Result<BatchTransaction, BuildError> {
+    let signer =
+        crate::get(MINTER_SUI_ADDRESS_KEY.to_string()).ok_or("Missing minter Sui address")?;
+
+    let total = batch
+        .iter()
+        .try_fold(0u64, |total, withdrawal| {
+            total.checked_add(withdrawal.amount)
+        })
+        .ok_or(BuildError::Withdrawal(
+            "Withdrawal batch amount overflow".to_string(),
+        ))?;
+
+    coins::refresh().await?;
+    let (gas, input_coins) = coins::select_withdrawal_coins(total)?;
+
+    let tx = sui::pay(
+        &signer,
+        input_coins,
+        batch.iter().map(|w| w.recipient.clone()).collect(),
+        batch.iter().map(|w| w.amount).collect(),
+        gas.object_id,
+        WITHDRAWAL_GAS_BUDGET,
+    )
+    .await
+    .map_err(|e| match e {
+        OutcallError::JsonRpc {
+            code: INVALID_PARAMS,
+            ..
+        } => BuildError::Withdrawal(e.to_string()),
+        e => BuildError::Unavailable(e.to_string()),
+    })?;
+
+    let digest = sui::transaction_digest(&tx.tx_bytes)?;
+    Ok(BatchTransaction { tx, digest })
+}
+
+pub fn to_status(withdrawal: Withdrawal) -> WithdrawalStatus {
+    WithdrawalStatus {
+        block_index: withdrawal.block_index,
+        from: withdrawal.from,
+        recipient: withdrawal.recipient,
+        amount: withdrawal.amount,
+        created_at: withdrawal.created_at,
+        state: withdrawal.state,
+        explorer_url: withdrawal
+            .tx_digest
+            .as_ref()
+            .map(|tx_digest| crate::explorer_tx_url(tx_digest)),
+        tx_digest: withdrawal.tx_digest,
+        error: withdrawal.error,
+    }
+}
+
+pub fn get_status(block_index: u64) -> Option<WithdrawalStatus> {
+    WITHDRAWALS
+        .with(|withdrawals| withdrawals.borrow().get(&block_index))
+        .map(to_status)
+}
+
+fn index_key(principal: &Principal, block_index: u64) -> KeyName {
+    // Zero padded so that the keys of one principal sort by block index.
+    KeyName(format!("{}:{block_index:020}", principal.to_text()))
+}
+
+/// Indexes the withdrawals queued before the index existed.
+pub fn backfill_index() {
+    let already_indexed = WITHDRAWAL_INDEX.with(|index| !index.borrow().is_empty());
+    if already_indexed {
+        return;
+    }
+    WITHDRAWALS.with(|withdrawals| {
+        WITHDRAWAL_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (block_index, withdrawal) in withdrawals.borrow().iter() {
+                index.insert(index_key(&withdrawal.from, block_index), ());
+            }
+        })
+    });
+}
+
+/// Returns one page of `principal`'s withdrawals, newest first. Only the
+/// index entries of `principal` up to the end of the page are read.
+pub fn get_for(principal: Principal, page: u64) -> Vec<WithdrawalStatus> {
+    let skip = usize::try_from(page)
+        .unwrap_or(usize::MAX)
+        .saturating_mul(WITHDRAWALS_PAGE_SIZE);
+    let prefix = format!("{}:", principal.to_text());
+    let block_indices: Vec<u64> = WITHDRAWAL_INDEX.with(|index| {
+        let index = index.borrow();
+        history::descending(&index, Some(index_key(&principal, u64::MAX)))
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+            .skip(skip)
+            .take(WITHDRAWALS_PAGE_SIZE)
+            .filter_map(|(key, _)| key.0.rsplit(':').next()?.parse::<u64>().ok())
+            .collect()
+    });
+    block_indices.into_iter().filter_map(get_status).collect()
+}
@@ -18,9 +18,68 @@ export const minterFactory = ({ IDL }) => {
     to_account: Account,
     amount: IDL.Text,
   });
+  const SuiOrigin = IDL.Record({
+    tx_digest: IDL.Text,
+    event_seq: IDL.Opt(IDL.Nat64),
+    sender: IDL.Opt(IDL.Text),
+    timestamp_ms: IDL.Opt(IDL.Nat64),
+  });
+  const MintRecord = IDL.Record({
+    block_index: IDL.Nat64,
+    amount: IDL.Nat64,
+    from: IDL.Text,
+    to: Account,
+    created_at: IDL.Nat64,
+    origin: IDL.Opt(SuiOrigin),
+  });
+  const WithdrawRecord = IDL.Record({
+    block_index: IDL.Nat64,
+    amount: IDL.Nat64,
+    from: IDL.Principal,
+    recipient: IDL.Opt(IDL.Text),
+    tx_digest: IDL.Text,
+    created_at: IDL.Nat64,
+  });
+  const Sort = IDL.Variant({ Ascending: IDL.Null, Descending: IDL.Null });
+  const HistoryQuery = IDL.Record({
+    start: IDL.Opt(IDL.Nat64),
+    limit: IDL.Opt(IDL.Nat64),
+    from_time: IDL.Opt(IDL.Nat64),
+    to_time: IDL.Opt(IDL.Nat64),
+    principal: IDL.Opt(IDL.Principal),
+    sort: IDL.Opt(Sort),
+  });
+  const MintRecordPage = IDL.Record({
+    records: IDL.Vec(MintRecord),
+    total: IDL.Opt(IDL.Nat64),
+    next: IDL.Opt(IDL.Nat64),
+  });
+  const WithdrawRecordPage = IDL.Record({
+    records: IDL.Vec(WithdrawRecord),
+    total: IDL.Opt(IDL.Nat64),
+    next: IDL.Opt(IDL.Nat64),
+  });
+  const CertifiedMintRecordPage = IDL.Record({
+    data: MintRecordPage,
+    certificate: IDL.Opt(IDL.Vec(IDL.Nat8)),
+    witness: IDL.Vec(IDL.Nat8),
+  });
+  const CertifiedWithdrawRecordPage = IDL.Record({
+    data: WithdrawRecordPage,
+    certificate: IDL.Opt(IDL.Vec(IDL.Nat8)),
+    witness: IDL.Vec(IDL.Nat8),
+  });
   return IDL.Service({
-    get_finalized_transactions: IDL.Func([], [IDL.Vec(IDL.Text)], []),
-    get_minted_transactions: IDL.Func([], [IDL.Vec(IDL.Text)], []),
+    get_finalized_transactions: IDL.Func(
+      [HistoryQuery],
+      [CertifiedWithdrawRecordPage],
+      ["query"]
+    ),
+    get_minted_transactions: IDL.Func(
+      [HistoryQuery],
+      [CertifiedMintRecordPage],
+      ["query"]
+    ),
     public_key: IDL.Func(
       [],
       [
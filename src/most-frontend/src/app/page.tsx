@@ -17,6 +17,18 @@ const host =
 
 const canisterId = "bd3sg-teaaa-aaaaa-qaaba-cai";
 
+const suiNetwork = process.env.NEXT_PUBLIC_SUI_NETWORK ?? "mainnet";
+
+// The latest page of history, newest first.
+const latestPage = {
+  start: [],
+  limit: [],
+  from_time: [],
+  to_time: [],
+  principal: [],
+  sort: [{ Descending: null }],
+};
+
 /**
  * @type {RequestInit}
  */
@@ -51,19 +63,19 @@ export default function Home() {
 
       setIsLoadingMinted(true);
       await agent.fetchRootKey();
-      const get_minted_transactions = await actor.get_minted_transactions();
-      const minted_trasactions = (get_minted_transactions as any)
+      const get_minted_transactions = await actor.get_minted_transactions(
+        latestPage
+      );
+      const minted_trasactions = (get_minted_transactions as any).data.records
         .map((transaction: any) => {
-          let parsed = JSON.parse(transaction);
-
           return {
-            block_index: Number(parsed.block_index),
+            block_index: Number(transaction.block_index),
             date: moment(
-              new Date(Number(BigInt(parsed.date) / BigInt(1000000)))
+              new Date(Number(transaction.created_at / BigInt(1000000)))
             ).format("DD-MM-YYYY hh:mm:ss"),
-            amount: parsed.amount,
-            from: parsed.from,
-            to: parsed.to,
+            amount: transaction.amount.toString(),
+            from: transaction.from,
+            to: transaction.to.owner.toText(),
           };
         })
         .sort((a: any, b: any) => a.block_index - b.block_index);
@@ -72,19 +84,17 @@ export default function Home() {
       setIsLoadingMinted(false);
       setIsLoadingFinalized(true);
 
-      const finalized = await actor.get_finalized_transactions();
-      const finalized_trasactions = (finalized as any)
+      const finalized = await actor.get_finalized_transactions(latestPage);
+      const finalized_trasactions = (finalized as any).data.records
         .map((transaction: any) => {
-          let parsed = JSON.parse(transaction);
-
           return {
-            block_index: Number(parsed.block_index),
+            block_index: Number(transaction.block_index),
             date: moment(
-              new Date(Number(BigInt(parsed.date) / BigInt(1000000)))
+              new Date(Number(transaction.created_at / BigInt(1000000)))
             ).format("DD-MM-YYYY hh:mm:ss"),
-            amount: parsed.amount,
-            from: parsed.from,
-            tx: parsed.tx,
+            amount: transaction.amount.toString(),
+            from: transaction.from.toText(),
+            tx: `https://suiscan.xyz/${suiNetwork}/tx/${transaction.tx_digest}`,
           };
         })
         .sort((a: any, b: any) => a.block_index - b.block_index);